@@ -1,11 +1,19 @@
 #!/bin/env python3
 
+import argparse
 import csv
 import json
 import random
 
 PARETO_ALPHA = 1.161 # 80/20 rule.  See also: https://en.wikipedia.org/wiki/Pareto_distribution#Relation_to_the_%22Pareto_principle%22
 
+parser = argparse.ArgumentParser(
+    description="Assigns the users from users.csv to rooms stored in rooms.json")
+parser.add_argument("--seed", type=int, default=None,
+                    help="Random seed, to generate the same rooms again")
+args = parser.parse_args()
+random.seed(args.seed)
+
 # First load the roster of users from users.csv
 users = []
 with open("users.csv", "r", encoding="utf-8") as csvfile:
@@ -11,8 +11,11 @@ parser.add_argument("num_users", type=int, default=1000, nargs="?",
                     help="Number of users to generate")
 parser.add_argument("-o", "--output", type=str, default="users.csv", nargs="?",
                     help="Output .csv file path")
+parser.add_argument("--seed", type=int, default=None,
+                    help="Random seed, to generate the same passwords again")
 
 args = parser.parse_args()
+random.seed(args.seed)
 
 with open(args.output, "w", encoding="utf-8") as csvfile:
     fieldnames = ["username", "password"]
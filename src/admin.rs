@@ -0,0 +1,322 @@
+//! Synapse admin API requests made directly through Goose.
+//!
+//! These endpoints aren't part of the Matrix client-server API, so they are
+//! sent with the [`GooseUser`] itself rather than a [`GooseMatrixClient`],
+//! under their own request names in the Goose metrics.
+//!
+//! [`GooseMatrixClient`]: crate::matrix::GooseMatrixClient
+
+use goose::{goose::GooseResponse, prelude::*};
+use hmac::{Hmac, Mac};
+use reqwest::StatusCode;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::Sha1;
+use thiserror::Error;
+
+use crate::matrix::is_expected_errcode;
+
+/// Path of the Synapse shared-secret registration endpoint.
+pub const SHARED_SECRET_REGISTER_PATH: &str = "/_synapse/admin/v1/register";
+
+/// Errors that can happen during shared-secret registration.
+#[derive(Error, Debug)]
+pub enum AdminError {
+    /// Goose failed to send the request.
+    #[error("request failed: {0}")]
+    Transaction(Box<TransactionError>),
+
+    /// No response or an unreadable response was received.
+    #[error(transparent)]
+    Reqwest(#[from] reqwest::Error),
+
+    /// The server answered with an error status.
+    #[error("server returned {status}: {body}")]
+    Status { status: StatusCode, body: String },
+}
+
+impl AdminError {
+    /// Returns the Matrix `errcode` of an error response, e.g. `M_USER_IN_USE`.
+    pub fn errcode(&self) -> Option<String> {
+        match self {
+            AdminError::Status { body, .. } => serde_json::from_str::<serde_json::Value>(body)
+                .ok()?
+                .get("errcode")?
+                .as_str()
+                .map(str::to_owned),
+            _ => None,
+        }
+    }
+}
+
+impl From<Box<TransactionError>> for AdminError {
+    fn from(err: Box<TransactionError>) -> Self {
+        AdminError::Transaction(err)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NonceResponse {
+    nonce: String,
+}
+
+/// The account created by a shared-secret registration.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SharedSecretRegistration {
+    pub user_id: String,
+    pub access_token: String,
+    pub device_id: String,
+    #[serde(default)]
+    pub home_server: Option<String>,
+}
+
+/// Computes the hex encoded HMAC-SHA1 Synapse expects for a registration.
+pub fn registration_mac(
+    shared_secret: &str,
+    nonce: &str,
+    username: &str,
+    password: &str,
+    admin: bool,
+) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(shared_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(nonce.as_bytes());
+    mac.update(b"\x00");
+    mac.update(username.as_bytes());
+    mac.update(b"\x00");
+    mac.update(password.as_bytes());
+    mac.update(b"\x00");
+    mac.update(if admin { b"admin" } else { b"notadmin" });
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Error responses with an errcode passed to `with_expected_errcodes` are
+// counted as successes in the Goose report, like the Matrix requests
+async fn into_result(
+    user: &GooseUser,
+    goose: GooseResponse,
+) -> Result<reqwest::Response, AdminError> {
+    let GooseResponse {
+        request: mut metric,
+        response,
+    } = goose;
+    let response = response?;
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await.unwrap_or_default();
+    let error = AdminError::Status { status, body };
+    if let Some(errcode) = error.errcode() {
+        if is_expected_errcode(&errcode) {
+            metric.error.clear();
+            let _ = user.set_success(&mut metric);
+        }
+    }
+    Err(error)
+}
+
+/// Registers a user through the Synapse admin API with the server's
+/// `registration_shared_secret`: fetches a nonce, then registers with the
+/// HMAC of the nonce and the user details.
+///
+/// Both requests are relative to the Goose `--host`, so they can be pointed
+/// at any server implementing the endpoint.
+pub async fn register_with_shared_secret(
+    user: &mut GooseUser,
+    shared_secret: &str,
+    username: &str,
+    password: &str,
+    admin: bool,
+) -> Result<SharedSecretRegistration, AdminError> {
+    let nonce_name = format!("{} (nonce)", SHARED_SECRET_REGISTER_PATH);
+    let goose = user
+        .get_named(SHARED_SECRET_REGISTER_PATH, &nonce_name)
+        .await?;
+    let nonce = into_result(user, goose)
+        .await?
+        .json::<NonceResponse>()
+        .await?
+        .nonce;
+
+    let body = json!({
+        "nonce": nonce,
+        "username": username,
+        "password": password,
+        "admin": admin,
+        "mac": registration_mac(shared_secret, &nonce, username, password, admin),
+    });
+    let request_builder = user
+        .get_request_builder(&GooseMethod::Post, SHARED_SECRET_REGISTER_PATH)?
+        .json(&body);
+    let goose_request = GooseRequest::builder()
+        .method(GooseMethod::Post)
+        .path(SHARED_SECRET_REGISTER_PATH)
+        .name(SHARED_SECRET_REGISTER_PATH)
+        .set_request_builder(request_builder)
+        .build();
+
+    let goose = user.request(goose_request).await?;
+    Ok(into_result(user, goose).await?.json().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use goose::{config::GooseConfiguration, prelude::*};
+    use reqwest::Url;
+    use serde_json::{json, Value as JsonValue};
+
+    use super::{register_with_shared_secret, registration_mac};
+
+    const SHARED_SECRET: &str = "shared";
+    const NONCE: &str = "abcdef";
+
+    // Stand-in for the Synapse endpoint: hands out a nonce, registers `bob`
+    // if the MAC matches and answers `M_USER_IN_USE` for anyone else
+    fn spawn_synapse() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                let (status, response) = if request_line.starts_with("GET ") {
+                    ("200 OK", json!({ "nonce": NONCE }))
+                } else {
+                    let body: JsonValue = serde_json::from_slice(&body).unwrap();
+                    let username = body["username"].as_str().unwrap();
+                    let mac = registration_mac(
+                        SHARED_SECRET,
+                        body["nonce"].as_str().unwrap(),
+                        username,
+                        body["password"].as_str().unwrap(),
+                        body["admin"].as_bool().unwrap(),
+                    );
+                    if body["mac"] != mac {
+                        ("403 Forbidden", json!({ "errcode": "M_FORBIDDEN" }))
+                    } else if username == "bob" {
+                        (
+                            "200 OK",
+                            json!({
+                                "user_id": "@bob:localhost",
+                                "access_token": "token",
+                                "device_id": "DEVICE",
+                                "home_server": "localhost",
+                            }),
+                        )
+                    } else {
+                        (
+                            "400 Bad Request",
+                            json!({
+                                "errcode": "M_USER_IN_USE",
+                                "error": "User ID already taken.",
+                            }),
+                        )
+                    }
+                };
+
+                let response = response.to_string();
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    response.len(),
+                    response
+                )
+                .unwrap();
+            }
+        });
+
+        Url::parse(&format!("http://{}", address)).unwrap()
+    }
+
+    fn goose_user(base_url: Url) -> GooseUser {
+        // Goose fills in the mitigation when it starts an attack
+        let mut configuration = GooseConfiguration::default();
+        configuration.co_mitigation = Some(GooseCoordinatedOmissionMitigation::Disabled);
+        GooseUser::single(base_url, &configuration).unwrap()
+    }
+
+    #[test]
+    fn registration_mac_matches_synapse() {
+        // Computed like the Synapse admin API tests do, with Python's hmac
+        assert_eq!(
+            registration_mac(SHARED_SECRET, NONCE, "bob", "abc123", true),
+            "31aa5b19dafcdb4e8ec18bf16798c22e22849509"
+        );
+        assert_eq!(
+            registration_mac(SHARED_SECRET, NONCE, "bob", "abc123", false),
+            "b58c82089187e570c8bb74d28cdff68fb82c5aab"
+        );
+    }
+
+    #[tokio::test]
+    async fn register_with_nonce() {
+        let mut user = goose_user(spawn_synapse());
+
+        let registration =
+            register_with_shared_secret(&mut user, SHARED_SECRET, "bob", "abc123", false)
+                .await
+                .unwrap();
+
+        assert_eq!(registration.user_id, "@bob:localhost");
+        assert_eq!(registration.access_token, "token");
+        assert_eq!(registration.device_id, "DEVICE");
+    }
+
+    #[tokio::test]
+    async fn register_existing_user() {
+        let mut user = goose_user(spawn_synapse());
+
+        let error = register_with_shared_secret(&mut user, SHARED_SECRET, "alice", "abc123", false)
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.errcode().as_deref(), Some("M_USER_IN_USE"));
+        assert!(error.to_string().starts_with("server returned 400"));
+    }
+
+    #[tokio::test]
+    async fn register_with_wrong_secret() {
+        let mut user = goose_user(spawn_synapse());
+
+        let error = register_with_shared_secret(&mut user, "wrong", "bob", "abc123", false)
+            .await
+            .unwrap_err();
+
+        assert_eq!(error.errcode().as_deref(), Some("M_FORBIDDEN"));
+    }
+}
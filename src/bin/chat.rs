@@ -3,144 +3,251 @@ use rand::seq::SliceRandom;
 use rand::Rng;
 
 use ruma_common::serde::Raw;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{
-    task::JoinHandle,
-    time::{Duration, Instant},
+use std::{
+    collections::{BTreeMap, HashMap},
+    process::exit,
+    sync::{Arc, Mutex, RwLock},
 };
+use tokio::{task::JoinHandle, time::Duration};
 
-use once_cell::sync::Lazy;
-use rand_distr::{Distribution, Exp, LogNormal};
-use weighted_rand::builder::*;
-// use duration_string::DurationString;
+use once_cell::sync::{Lazy, OnceCell};
 use serde_json::{json, value::to_raw_value};
 
 // use matrix_sdk::Client;
 use matrix_sdk::ruma::{
     // events::room::message::SyncRoomMessageEvent,
-    events::room::message::OriginalSyncRoomMessageEvent,
+    events::{room::message::OriginalSyncRoomMessageEvent, AnySyncMessageLikeEvent},
+    OwnedEventId,
     OwnedRoomId,
     TransactionId,
 };
 
 use matrix_goose::{
-    matrix::{config::SyncSettings, room::Room, GooseMatrixClient, GOOSE_USERS},
-    task_sleep, CANCELED,
+    action,
+    cli::{CliArgs, CliOption},
+    delivery::{self, DeliveryTracker, OrderingTracker, Probe},
+    federation,
+    fixtures::{CreatedRooms, User, CREATED_ROOMS_FILE},
+    matrix::{config::SyncSettings, room::Room, Ctx, GooseMatrixClient, GOOSE_USERS},
+    metrics::{ActionMetrics, Histogram, RATE_LIMITS},
+    report, rng,
+    scheduler::Scheduler,
+    session::{LoginKind, SessionCache, SESSIONS_FILE},
+    task_sleep,
+    workload::{ActionConfig, Persona, RoomPreference, WorkloadConfig},
+    CANCELED,
 };
 
-#[derive(Debug, Clone, serde::Deserialize)]
-struct User {
-    username: String,
-    password: String,
-}
+// The messages received by the sync task, shared with the logic task
+type RoomMessages = Arc<Mutex<HashMap<OwnedRoomId, Vec<OriginalSyncRoomMessageEvent>>>>;
 
 // TODO: Switch to using the client store instead of user session data
 #[derive(Debug)]
 struct ClientData {
     room_id: Option<OwnedRoomId>,
     room_tokens: HashMap<OwnedRoomId, String>,
-    room_messages: HashMap<OwnedRoomId, Vec<OriginalSyncRoomMessageEvent>>,
+    room_messages: RoomMessages,
     sync_forever_handle: JoinHandle<()>,
 }
 
-// #[derive(Debug, Clone)]
-enum TaskIndex {
-    DoNothing,
-    SendText,
-    LookAtRoom,
-    PaginateRoom,
-    GoAFK,
-    ChangeDisplayName,
-    SendImage,
-    SendReaction,
-}
+const DEFAULT_WORKLOAD: &str = include_str!("../../workloads/default.toml");
 
-impl From<usize> for TaskIndex {
-    fn from(value: usize) -> Self {
-        match value {
-            0 => Self::DoNothing,
-            1 => Self::SendText,
-            2 => Self::LookAtRoom,
-            3 => Self::PaginateRoom,
-            4 => Self::GoAFK,
-            5 => Self::ChangeDisplayName,
-            6 => Self::SendImage,
-            7 => Self::SendReaction,
-            _ => panic!("Invalid enum index"),
-        }
-    }
-}
+// Name of the only scenario, which the requests of the matrix clients are reported under
+const SCENARIO: &str = "Default";
 
-static mut USERS: Vec<User> = Vec::new();
-static USERS_READER: &Vec<User> = unsafe { &USERS };
+static USERS: OnceCell<Vec<User>> = OnceCell::new();
 
-// Note that a single goose user reference may required shared ownership between two
-// threads (sync_forever and logic thread) depending on the current state of the tokio
-// runtime task scheduler.
-static mut CLIENTS: Lazy<HashMap<usize, Arc<GooseMatrixClient>>> = Lazy::new(HashMap::new);
-static ATTACK_START: Lazy<Instant> = Lazy::new(Instant::now);
+// Each client is shared between the sync task and the logic task of its user
+static CLIENTS: Lazy<RwLock<HashMap<usize, Arc<GooseMatrixClient>>>> = Lazy::new(Default::default);
+static SCHEDULERS: OnceCell<HashMap<String, Scheduler>> = OnceCell::new();
+static PERSONAS: OnceCell<HashMap<String, Persona>> = OnceCell::new();
+static WORKLOAD: OnceCell<WorkloadConfig> = OnceCell::new();
+static USER_PERSONAS: OnceCell<Vec<String>> = OnceCell::new();
+static BIGGEST_ROOM: OnceCell<OwnedRoomId> = OnceCell::new();
+static SESSIONS: Lazy<SessionCache> = Lazy::new(|| SessionCache::load(SESSIONS_FILE));
+static DELIVERY_LATENCY: Lazy<Histogram> = Lazy::new(Histogram::default);
+static ACTIONS: Lazy<ActionMetrics> = Lazy::new(ActionMetrics::default);
+static DELIVERIES: Lazy<DeliveryTracker> = Lazy::new(DeliveryTracker::default);
+static ORDERING: Lazy<OrderingTracker> = Lazy::new(OrderingTracker::default);
+static CHECK_ORDERING: OnceCell<bool> = OnceCell::new();
 
 const lorem_ipsum_text: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi ut aliquip ex ea commodo consequat. Duis aute irure dolor in reprehenderit in voluptate velit esse cillum dolore eu fugiat nulla pariatur. Excepteur sint occaecat cupidatat non proident, sunt in culpa qui officia deserunt mollit anim id est laborum.";
 
 async fn get_client(index: usize) -> Arc<GooseMatrixClient> {
-    unsafe { return Arc::clone(CLIENTS.get(&index).unwrap()) }
+    Arc::clone(&CLIENTS.read().unwrap()[&index])
+}
+
+fn get_persona(index: usize) -> &'static Persona {
+    &PERSONAS.get().unwrap()[&USER_PERSONAS.get().unwrap()[index]]
+}
+
+fn get_action(index: usize, name: &str) -> Option<&'static ActionConfig> {
+    get_persona(index).action(name)
+}
+
+// Sleeps for the think-time configured for the given action, if any
+async fn think(index: usize, name: &str) {
+    if let Some(think_time) = get_action(index, name).and_then(|action| action.think_time.as_ref())
+    {
+        let delay = rng::with_user_rng(index, |rng| think_time.sample(rng));
+        task_sleep(delay, true).await;
+    }
 }
 
 async fn setup(_user: &mut GooseUser) -> TransactionResult {
     println!("Setting up loadtest...");
 
     // Load users from csv
-    unsafe {
-        match csv::Reader::from_path("users.csv") {
-            Ok(mut reader) => {
-                for entry in reader.deserialize::<User>() {
-                    match entry {
-                        Ok(record) => {
-                            // println!("{:?}", record);
-                            USERS.push(record);
-                        }
-                        Err(err) => panic!("Error reading user from users.csv: {}", err),
+    let mut users = Vec::new();
+    match csv::Reader::from_path("users.csv") {
+        Ok(mut reader) => {
+            for entry in reader.deserialize::<User>() {
+                match entry {
+                    Ok(record) => {
+                        // println!("{:?}", record);
+                        users.push(record);
                     }
+                    Err(err) => panic!("Error reading user from users.csv: {}", err),
                 }
             }
-            Err(err) => panic!("Error reading users.csv: {}", err),
         }
+        Err(err) => panic!("Error reading users.csv: {}", err),
+    }
+
+    // Reserve room for all the clients to prevent multiple re-allocations
+    CLIENTS.write().unwrap().reserve(users.len());
+    let _ = USERS.set(users);
 
-        // Resize CLIENTS to prevent multiple re-allocations
-        CLIENTS.reserve(USERS.len());
+    // Personas focusing on the biggest room need to know which one it is
+    if PERSONAS
+        .get()
+        .unwrap()
+        .values()
+        .any(|persona| persona.rooms == RoomPreference::Biggest)
+    {
+        match CreatedRooms::load(CREATED_ROOMS_FILE) {
+            Ok(created_rooms) => {
+                if let Some((name, room)) = created_rooms.biggest() {
+                    println!("Biggest room is {} ({})", name, room.room_id);
+                    let _ = BIGGEST_ROOM.set(room.room_id.to_owned());
+                }
+            }
+            Err(err) => panic!("Error reading {}: {}", CREATED_ROOMS_FILE, err),
+        }
     }
 
+    for csv_user in USERS.get().unwrap().iter() {
+        if let Some(persona) = &csv_user.persona {
+            if !PERSONAS.get().unwrap().contains_key(persona) {
+                panic!(
+                    "Unknown persona '{}' for user {} in users.csv",
+                    persona, csv_user.username
+                );
+            }
+        }
+    }
+
+    let explicit: Vec<Option<String>> = USERS
+        .get()
+        .unwrap()
+        .iter()
+        .map(|csv_user| csv_user.persona.clone())
+        .collect();
+    let _ = USER_PERSONAS.set(WORKLOAD.get().unwrap().assign_personas(&explicit));
+
     Ok(())
 }
 
-async fn teardown(_user: &mut GooseUser) -> TransactionResult {
+async fn teardown(user: &mut GooseUser) -> TransactionResult {
     println!("Tearing down loadtest...");
 
+    match SESSIONS.save() {
+        Ok(()) => println!("Saved {} sessions to {}", SESSIONS.len(), SESSIONS_FILE),
+        Err(err) => println!("Error writing {}: {}", SESSIONS_FILE, err),
+    }
+
+    // Record how many users ended up with each persona
+    let num_users = user
+        .config
+        .users
+        .unwrap_or(0)
+        .min(USERS.get().unwrap().len());
+    let mut persona_counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for index in 0..num_users {
+        *persona_counts
+            .entry(get_persona(index).name.as_str())
+            .or_default() += 1;
+    }
+
+    let summary: Vec<String> = persona_counts
+        .iter()
+        .map(|(name, count)| format!("{}: {} users", name, count))
+        .collect();
+    report::add_section("Personas", summary.join("\n"));
+
+    report::add_section("Actions", ACTIONS.summary());
+    report::add_section("Delivery latency", DELIVERY_LATENCY.summary("ms"));
+    report::add_section("Delivery loss", DELIVERIES.report().summary());
+    if *CHECK_ORDERING.get().unwrap() {
+        report::add_section("Delivery ordering", ORDERING.summary());
+    }
+
+    if federation::is_federated(USERS.get().unwrap()) {
+        let users = &USERS.get().unwrap()[..num_users];
+        report::add_section(
+            "Homeservers",
+            federation::summary(users, user.base_url.as_str()),
+        );
+    }
+
     Ok(())
 }
 
 async fn on_start(user: &mut GooseUser) -> TransactionResult {
     let user_index = user.weighted_users_index;
-    let client: Arc<GooseMatrixClient>;
-    unsafe {
-        let host = user.base_url.to_owned();
-        GOOSE_USERS.push(user);
-
-        let static_client_ref = Arc::new(GooseMatrixClient::new(host, user_index).await.unwrap());
-        CLIENTS.insert(user_index, static_client_ref);
-        client = Arc::clone(&CLIENTS[&user_index]);
+    let csv_user = &USERS.get().unwrap()[user_index];
+    federation::set_homeserver(user, csv_user)?;
+
+    // Register the user the matrix API sends its Goose requests for
+    GOOSE_USERS.register(user, 0, SCENARIO).unwrap();
+
+    let host = user.base_url.to_owned();
+    let mut builder = GooseMatrixClient::builder(user_index).homeserver_url(host);
+
+    // Report requests per persona when the workload defines them, and per
+    // homeserver when the users span several
+    let mut labels = Vec::new();
+    if !WORKLOAD.get().unwrap().personas.is_empty() {
+        labels.push(format!("[{}]", get_persona(user_index).name));
+    }
+    labels.extend(federation::homeserver_label(user, USERS.get().unwrap()));
+    if !labels.is_empty() {
+        builder = builder.request_name_prefix(labels.join(" "));
     }
 
-    let csv_user = &USERS_READER[user_index];
+    let client = Arc::new(builder.build().await.unwrap());
+    CLIENTS
+        .write()
+        .unwrap()
+        .insert(user_index, Arc::clone(&client));
+
     let username = csv_user.username.to_owned();
     let password = csv_user.password.to_owned();
     let mut retries = 3;
 
     while retries > 0 {
-        match client.login_username(&username, &password).send().await {
-            Ok(_) => {
-                println!("[{}] Logged in successfully", username);
+        match SESSIONS.login(&client, &username, &password).await {
+            Ok(login_kind) => {
+                match login_kind {
+                    LoginKind::Restored => println!("[{}] Restored cached session", username),
+                    LoginKind::Password => println!("[{}] Logged in successfully", username),
+                }
+                let room_messages = RoomMessages::default();
+                client.add_event_handler_context(Arc::clone(&room_messages));
                 client.add_event_handler(on_room_message);
+                client.add_event_handler(on_probe_message);
+                client.add_event_handler(on_message_like_event);
+                DELIVERIES.start(client.user_id().unwrap());
 
                 // Replicate `sync` method behavior from the SDK client
                 let mut last_sync_time: Option<std::time::Instant> = None;
@@ -151,9 +258,8 @@ async fn on_start(user: &mut GooseUser) -> TransactionResult {
                     let mut sync_settings = SyncSettings::default();
 
                     loop {
-                        // We cannot use the SDK's `sync` forever method because we can
-                        // get segmentation faults if we attempt to abort the task while
-                        // having an open TCP connection
+                        // Not using the SDK's `sync` forever method, so that the loop
+                        // stops once the load test is canceled
                         match client.sync_once(sync_settings.clone()).await {
                             Ok(response) => {
                                 sync_settings = sync_settings.token(response.next_batch.clone());
@@ -177,7 +283,7 @@ async fn on_start(user: &mut GooseUser) -> TransactionResult {
                 user.set_session_data(ClientData {
                     room_id: None,
                     room_tokens: HashMap::new(),
-                    room_messages: HashMap::new(),
+                    room_messages,
                     sync_forever_handle: handle,
                 });
 
@@ -202,6 +308,7 @@ async fn on_start(user: &mut GooseUser) -> TransactionResult {
 
 async fn on_stop(user: &mut GooseUser) -> TransactionResult {
     // println!("Stopping goose user {}...", user.weighted_users_index);
+    let user_index = user.weighted_users_index;
 
     if let Some(client_data) = user.get_session_data::<ClientData>() {
         // Drop lock after updating canceled status
@@ -211,119 +318,123 @@ async fn on_stop(user: &mut GooseUser) -> TransactionResult {
             }
         }
 
-        // We cannot directly abort sync_forever task since segmentation faults could
-        // occur if task is aborted having an open TCP connection
+        // Let the sync_forever task finish its last request rather than aborting it,
+        // so that the response is still recorded
         while !client_data.sync_forever_handle.is_finished() {
             // Wait until timeout expires or sync response is received
             task_sleep(1.0, false).await;
         }
+
+        // Look for the events that didn't show up in the sync in the room history
+        let client = get_client(user_index).await;
+        DELIVERIES.stop(
+            client.user_id().unwrap(),
+            client
+                .joined_rooms()
+                .iter()
+                .map(|room| room.room_id().to_owned()),
+        );
+        DELIVERIES.backfill(&client).await;
     }
 
     Ok(())
 }
 
-// The Goose task scheduler is insufficient for our use-case:
-//   1. Transaction ordering for a given scenario is deterministic and not random
-//   2. Scenario scheduling can be set to 'random', but Goose allocates a set of users
-//      only at start to a given scenario that ALWAYS runs the transactions within its
-//      own scenario for the remainder of the program.
-// Thus to replicate similar behavior to Locust, we have to create our own scheduler to
-// achieve a weighted, non-deterministic selection of user actions
+// Runs the weighted random user actions of the user's persona, see
+// `matrix_goose::scheduler`
 async fn task_scheduler(user: &mut GooseUser) -> TransactionResult {
-    // let runtime: Duration = DurationString::from_string(user.config.run_time.to_owned()).unwrap().into();
-    // Goose seems to internally convert the '--run-time' time string to seconds
-    let runtime = Duration::from_secs(user.config.run_time.parse::<u64>().unwrap());
-
-    // Drop lock after checking canceled status
-    {
-        // Goose seems to re-run the scheduler transaction upon the
-        // GooseAttack phase decrease...
-        if *CANCELED.read().await || ATTACK_START.elapsed() > runtime {
-            return Ok(());
-        }
-    }
+    let persona = get_persona(user.weighted_users_index);
+    SCHEDULERS.get().unwrap()[&persona.name].run(user).await
+}
 
-    // Scheduler setup
-    let index_weights = [11, 6, 4, 2, 1, 1, 0, 1];
-    let task_gen = WalkerTableBuilder::new(&index_weights).build();
+// Picks one of the joined rooms, favouring the room sizes preferred by the user's persona
+fn choose_room(client: &GooseMatrixClient, index: usize) -> Option<OwnedRoomId> {
+    let mut preference = get_persona(index).rooms;
 
-    // Task scheduler loop
-    loop {
-        // Drop lock after checking canceled status
-        {
-            // Goose is unable to terminate users upon switching to the decrease phase
-            if *CANCELED.read().await || ATTACK_START.elapsed() > runtime {
-                break;
+    if preference == RoomPreference::Biggest {
+        match BIGGEST_ROOM.get() {
+            Some(room_id) if client.get_joined_room(room_id).is_some() => {
+                return Some(room_id.to_owned())
             }
+            _ => preference = RoomPreference::Large,
         }
-
-        let index = task_gen.next_rng(&mut rand::thread_rng());
-        match TaskIndex::from(index) {
-            TaskIndex::DoNothing => {
-                let _ = do_nothing(user).await;
-            }
-            TaskIndex::SendText => {
-                let _ = send_text(user).await;
-            }
-            TaskIndex::LookAtRoom => {
-                let _ = look_at_room(user).await;
-            }
-            TaskIndex::PaginateRoom => {
-                let _ = paginate_room(user).await;
-            }
-            TaskIndex::GoAFK => {
-                let _ = go_afk(user).await;
-            }
-            TaskIndex::ChangeDisplayName => {
-                let _ = change_displayname(user).await;
-            }
-            TaskIndex::SendImage => {
-                let _ = send_image(user).await;
-            }
-            TaskIndex::SendReaction => {
-                let _ = send_reaction(user).await;
-            }
-        }
-
-        task_sleep(0.1, true).await;
     }
 
-    Ok(())
+    // The joined rooms come in no particular order, so they are sorted for a
+    // seeded run to pick the same rooms again
+    let mut rooms = client.joined_rooms();
+    rooms.sort_by(|a, b| a.room_id().cmp(b.room_id()));
+
+    rng::with_user_rng(index, |rng| {
+        let room = match preference {
+            RoomPreference::Any => rooms.choose(rng),
+            RoomPreference::Small => rooms
+                .choose_weighted(rng, |room| 1.0 / room.joined_members_count().max(1) as f64)
+                .ok(),
+            RoomPreference::Large | RoomPreference::Biggest => rooms
+                .choose_weighted(rng, |room| room.joined_members_count().max(1) as f64)
+                .ok(),
+        };
+
+        room.map(|joined| joined.room_id().to_owned())
+    })
 }
 
 async fn on_room_message(
     event: OriginalSyncRoomMessageEvent,
     room: Room,
-    client: GooseMatrixClient,
+    Ctx(room_messages): Ctx<RoomMessages>,
 ) {
-    // Consider protecting with RwLock, or enforce sync and user tasks run only on the same thread
-    let user: &mut GooseUser =
-        unsafe { GOOSE_USERS[client.inner.goose_user_index].as_mut().unwrap() };
-    let client_data = user.get_session_data_mut::<ClientData>().unwrap();
+    let mut room_messages = room_messages.lock().unwrap();
     // println!("Got message '{}' in room {}", event.content.body(), room.room_id());
 
     // Add the new messages to whatever we had before (if anything)
-    match client_data
-        .room_messages
-        .get_mut(&room.room_id().to_owned())
-    {
+    match room_messages.get_mut(&room.room_id().to_owned()) {
         Some(messages) => {
             messages.push(event);
         }
         None => {
-            let mut room_messages = Vec::new();
-            room_messages.push(event.to_owned());
-            client_data
-                .room_messages
-                .insert(room.room_id().to_owned(), room_messages);
+            room_messages.insert(room.room_id().to_owned(), vec![event]);
         }
     }
 }
 
-async fn do_nothing(_user: &mut GooseUser) -> TransactionResult {
-    let exp = Exp::new(0.1).unwrap();
-    let delay = exp.sample(&mut rand::thread_rng());
-    task_sleep(delay, true).await;
+// Records the delivery latency of the messages sent by other users of this run,
+// and checks their order when enabled
+async fn on_probe_message(
+    event: Raw<OriginalSyncRoomMessageEvent>,
+    room: Room,
+    client: GooseMatrixClient,
+) {
+    let user_id = match client.user_id() {
+        Some(user_id) => user_id,
+        None => return,
+    };
+
+    if let Some((sender, probe)) = delivery::received(&event) {
+        if *sender == *user_id {
+            return;
+        }
+
+        DELIVERY_LATENCY.record(probe.latency_ms());
+        if let Some(seq) = probe.seq {
+            ORDERING.received(user_id, &sender, room.room_id(), seq);
+        }
+    }
+}
+
+// Records which of the tracked events reached the user
+async fn on_message_like_event(event: Raw<AnySyncMessageLikeEvent>, client: GooseMatrixClient) {
+    if let (Ok(Some(event_id)), Some(user_id)) = (
+        event.get_field::<OwnedEventId>("event_id"),
+        client.user_id(),
+    ) {
+        DELIVERIES.received(&event_id, user_id);
+    }
+}
+
+async fn do_nothing(user: &mut GooseUser) -> TransactionResult {
+    think(user.weighted_users_index, "do_nothing").await;
 
     Ok(())
 }
@@ -334,6 +445,7 @@ async fn send_text(user: &mut GooseUser) -> TransactionResult {
     let username = client.user_id().unwrap().localpart();
     use ruma::api::client::message::send_message_event::v3::Request as MessageRequest;
     use ruma::events::room::message::RoomMessageEventContent as RoomMessage;
+    use ruma_common::events::MessageLikeEventType;
 
     // Send the typing notification like a real client would
     let room_id;
@@ -349,6 +461,7 @@ async fn send_text(user: &mut GooseUser) -> TransactionResult {
         None => return Ok(()),
     }
 
+    let mut action = ACTIONS.start("Send message");
     if client
         .get_joined_room(&room_id)
         .unwrap()
@@ -357,22 +470,41 @@ async fn send_text(user: &mut GooseUser) -> TransactionResult {
         .is_err()
     {
         println!("[{}] failed sending typing notification", username);
+        action.fail();
     }
 
     // Sleep while we pretend the user is banging on the keyboard
-    let exp = Exp::new(1.0 / 5.0).unwrap();
-    let delay = exp.sample(&mut rand::thread_rng());
-    task_sleep(delay, true).await;
+    action.exclude(think(user_index, "send_text")).await;
 
     let words: Vec<&str> = lorem_ipsum_text.split(' ').collect();
-    let log_normal = LogNormal::new(1.0, 1.0).unwrap();
-    let mut message_len = f64::round(log_normal.sample(&mut rand::thread_rng())) as usize;
+    let mut message_len = match get_action(user_index, "send_text")
+        .and_then(|action| action.message_length.as_ref())
+    {
+        Some(message_length) => f64::round(rng::with_user_rng(user_index, |rng| {
+            message_length.sample(rng)
+        })) as usize,
+        None => words.len(),
+    };
     message_len = usize::max(usize::min(message_len, words.len()), 1);
 
+    // The probe carries the send time, to measure how long the message takes to reach the others
     let content = RoomMessage::text_plain(words[0..message_len].join(" "));
-    let request = MessageRequest::new(room_id.to_owned(), TransactionId::new(), &content).unwrap();
-    if client.send(request, None).await.is_err() {
-        println!("[{}] failed to send/chat in room [{}]", username, room_id);
+    let mut probe = Probe::new();
+    if *CHECK_ORDERING.get().unwrap() {
+        probe = probe.with_sequence(ORDERING.next_seq(client.user_id().unwrap(), &room_id));
+    }
+    let request = MessageRequest::new_raw(
+        room_id.to_owned(),
+        TransactionId::new(),
+        MessageLikeEventType::RoomMessage,
+        probe.attach(&content),
+    );
+    match client.send(request, None).await {
+        Ok(response) => DELIVERIES.sent(&response.event_id, &room_id, client.user_id().unwrap()),
+        Err(_) => {
+            println!("[{}] failed to send/chat in room [{}]", username, room_id);
+            action.fail();
+        }
     }
 
     Ok(())
@@ -386,8 +518,8 @@ async fn look_at_room(user: &mut GooseUser) -> TransactionResult {
     use ruma::api::client::receipt::create_receipt::v3::ReceiptType;
     use ruma_common::events::receipt::ReceiptThread;
 
-    let room_id = match client.joined_rooms().choose(&mut rand::thread_rng()) {
-        Some(joined) => joined.room_id().to_owned(),
+    let room_id = match choose_room(&client, user_index) {
+        Some(room_id) => room_id,
         None => return Ok(()),
     };
 
@@ -456,15 +588,24 @@ async fn look_at_room(user: &mut GooseUser) -> TransactionResult {
     //     #         if thumb_mxc is not None:
     //     #             self.download_matrix_media(thumb_mxc)
 
-    if let Some(events) = client_data.room_messages.get(&room_id) {
-        let event_id = events.last().unwrap().event_id.to_owned();
-        if client
-            .get_joined_room(&room_id)
-            .unwrap()
-            .send_single_receipt(ReceiptType::Read, ReceiptThread::Unthreaded, event_id)
-            .await
-            .is_err()
-        {
+    // The messages can't stay locked while sending the receipt
+    let last_event_id = client_data
+        .room_messages
+        .lock()
+        .unwrap()
+        .get(&room_id)
+        .map(|events| events.last().unwrap().event_id.to_owned());
+
+    if let Some(event_id) = last_event_id {
+        let room = client.get_joined_room(&room_id).unwrap();
+        let result = ACTIONS
+            .scope(
+                "Open room",
+                room.send_single_receipt(ReceiptType::Read, ReceiptThread::Unthreaded, event_id),
+            )
+            .await;
+
+        if result.is_err() {
             println!(
                 "[{}] failed to update read marker in room [{}]",
                 username, room_id
@@ -486,8 +627,8 @@ async fn paginate_room(user: &mut GooseUser) -> TransactionResult {
     let client = get_client(user_index).await;
     let username = client.user_id().unwrap().localpart();
 
-    let room_id = match client.joined_rooms().choose(&mut rand::thread_rng()) {
-        Some(joined) => joined.room_id().to_owned(),
+    let room_id = match choose_room(&client, user_index) {
+        Some(room_id) => room_id,
         None => return Ok(()),
     };
 
@@ -522,14 +663,12 @@ async fn paginate_room(user: &mut GooseUser) -> TransactionResult {
 async fn go_afk(user: &mut GooseUser) -> TransactionResult {
     let user_index = user.weighted_users_index;
 
-    let csv_user = &USERS_READER[user_index];
+    let csv_user = &USERS.get().unwrap()[user_index];
     let username = &csv_user.username.to_owned();
     println!("[{}] going away from keyboard", username);
 
     // Generate large(ish) random away time.
-    let exp = Exp::new(1.0 / 600.0).unwrap(); // Expected value = 10 minutes
-    let delay = exp.sample(&mut rand::thread_rng());
-    task_sleep(delay, true).await;
+    think(user_index, "go_afk").await;
 
     Ok(())
 }
@@ -540,7 +679,7 @@ async fn change_displayname(user: &mut GooseUser) -> TransactionResult {
     let username = client.user_id().unwrap().localpart();
 
     let user_number = *username.split('.').collect::<Vec<&str>>().last().unwrap();
-    let random_number = rand::thread_rng().gen_range(1..1000);
+    let random_number = rng::with_user_rng(user_index, |rng| rng.gen_range(1..1000));
     let new_name = format!("User {} (random={})", user_number, random_number);
 
     if client
@@ -574,48 +713,58 @@ async fn send_reaction(user: &mut GooseUser) -> TransactionResult {
 
     // Pick a recent message from the selected room, and react to it
 
-    let room_id = match client.joined_rooms().choose(&mut rand::thread_rng()) {
-        Some(joined) => joined.room_id().to_owned(),
+    let room_id = match choose_room(&client, user_index) {
+        Some(room_id) => room_id,
         None => return Ok(()),
     };
 
-    if let Some(messages) = client_data.room_messages.get(&room_id) {
+    // The messages can't stay locked while sending the reaction
+    let content = {
+        let room_messages = client_data.room_messages.lock().unwrap();
+        let messages = match room_messages.get(&room_id) {
+            Some(messages) => messages,
+            None => return Ok(()),
+        };
+
         let slice_start = if messages.len() > 10 {
             messages.len() - 11
         } else {
             0
         };
-        let message = messages[slice_start..]
-            .choose(&mut rand::thread_rng())
-            .unwrap();
-        let reaction = ["💩", "👍", "❤️", "👎", "🤯", "😱", "👏"].choose(&mut rand::thread_rng());
-        let content = to_raw_value(&json!({
+        let (message, reaction) = rng::with_user_rng(user_index, |rng| {
+            (
+                messages[slice_start..].choose(rng).unwrap(),
+                ["💩", "👍", "❤️", "👎", "🤯", "😱", "👏"].choose(rng),
+            )
+        });
+        to_raw_value(&json!({
             "m.relates_to": {
                 "rel_type": "m.annotation",
                 "event_id": message.event_id,
                 "key": reaction,
             }
         }))
-        .unwrap();
+        .unwrap()
+    };
 
-        // # Prevent errors with reacting to the same message with the same reaction
-        // if (message, reaction) in self.reacted_messages:
-        //     return
-        // else:
-        //     self.reacted_messages.append((message, reaction))
-
-        let request = MessageRequest::new_raw(
-            room_id.to_owned(),
-            TransactionId::new(),
-            MessageLikeEventType::Reaction,
-            Raw::from_json(content),
-        );
-        if client.send(request, None).await.is_err() {
-            println!(
-                "[{}] failed to send reaction in room [{}]",
-                username, room_id
-            );
-        }
+    // # Prevent errors with reacting to the same message with the same reaction
+    // if (message, reaction) in self.reacted_messages:
+    //     return
+    // else:
+    //     self.reacted_messages.append((message, reaction))
+
+    let request = MessageRequest::new_raw(
+        room_id.to_owned(),
+        TransactionId::new(),
+        MessageLikeEventType::Reaction,
+        Raw::from_json(content),
+    );
+    match client.send(request, None).await {
+        Ok(response) => DELIVERIES.sent(&response.event_id, &room_id, client.user_id().unwrap()),
+        Err(_) => println!(
+            "[{}] failed to send reaction in room [{}]",
+            username, room_id
+        ),
     }
 
     Ok(())
@@ -625,11 +774,97 @@ async fn send_reaction(user: &mut GooseUser) -> TransactionResult {
 async fn main() -> Result<(), GooseError> {
     println!("Starting matrix user chat loadtest...");
 
+    let (configuration, args) = CliArgs::parse(&[
+        CliOption {
+            name: "workload",
+            meta: "FILE",
+            help: "Sets the workload file (.toml or .json)",
+        },
+        CliOption {
+            name: "seed",
+            meta: "SEED",
+            help: "Sets the random seed, to replay the actions of a previous run",
+        },
+        CliOption {
+            name: "max-loss",
+            meta: "RATIO",
+            help: "Fails the run when a larger share of the messages was not delivered",
+        },
+        CliOption {
+            name: "check-ordering",
+            meta: "",
+            help: "Checks that the messages of each sender arrive in the order they were sent",
+        },
+    ]);
+    CHECK_ORDERING.set(args.is_set("check-ordering")).unwrap();
+
+    // Record the seed so the run can be replayed with --seed
+    let seed = rng::set_seed(args.get_parsed("seed"));
+    println!("Using random seed {}", seed);
+    report::add_section("Seed", seed.to_string());
+
+    let (workload, workload_source) = match args.get("workload") {
+        Some(path) => match WorkloadConfig::load(path) {
+            Ok(workload) => (workload, path.to_owned()),
+            Err(err) => panic!("Error reading workload {}: {}", path, err),
+        },
+        None => (
+            toml::from_str(DEFAULT_WORKLOAD).unwrap(),
+            "built-in default".to_owned(),
+        ),
+    };
+
+    let scheduler = Scheduler::new()
+        .register_action(action!(do_nothing))
+        .register_action(action!(send_text))
+        .register_action(action!(look_at_room))
+        .register_action(action!(paginate_room))
+        .register_action(action!(go_afk))
+        .register_action(action!(change_displayname))
+        .register_action(action!(send_image))
+        .register_action(action!(send_reaction));
+
+    if let Err(err) = workload.validate(&scheduler.action_names()) {
+        panic!("Error validating workload {}: {}", workload_source, err);
+    }
+
+    // Echo the workload so the run can be reproduced from its report
+    report::add_section(
+        "Workload",
+        format!("# {}\n{}", workload_source, workload.to_toml()),
+    );
+
+    let personas = workload.personas();
+    let schedulers = personas
+        .iter()
+        .map(|persona| {
+            (
+                persona.name.to_owned(),
+                scheduler.clone().set_persona(persona),
+            )
+        })
+        .collect();
+    let _ = SCHEDULERS.set(schedulers);
+    PERSONAS
+        .set(
+            personas
+                .into_iter()
+                .map(|persona| (persona.name.to_owned(), persona))
+                .collect(),
+        )
+        .unwrap();
+    WORKLOAD.set(workload).unwrap();
+
+    // Goose can't include the rate limits in its own running metrics
+    if let Some(interval) = configuration.running_metrics {
+        RATE_LIMITS.print_running(Duration::from_secs(interval as u64));
+    }
+
     // Run test
-    GooseAttack::initialize()?
+    GooseAttack::initialize_with_config(configuration.clone())?
         .test_start(transaction!(setup))
         .register_scenario(
-            scenario!("Default")
+            scenario!(SCENARIO)
                 .register_transaction(transaction!(on_start).set_on_start().set_name("On start"))
                 .register_transaction(transaction!(task_scheduler).set_name("Scheduler"))
                 .register_transaction(transaction!(on_stop).set_on_stop().set_name("On stop"))
@@ -640,5 +875,18 @@ async fn main() -> Result<(), GooseError> {
         .execute()
         .await?;
 
+    report::write(&configuration)?;
+
+    if let Some(max_loss) = args.get_parsed::<f64>("max-loss") {
+        let loss_ratio = DELIVERIES.report().loss_ratio();
+        if loss_ratio > max_loss {
+            eprintln!(
+                "Delivery loss of {:.4} is above the maximum of {}",
+                loss_ratio, max_loss
+            );
+            exit(1);
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,299 @@
+use goose::prelude::*;
+use once_cell::sync::{Lazy, OnceCell};
+use serde_json::json;
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use matrix_sdk::ruma::api::client::uiaa::{AuthData, UiaaInfo};
+use ruma_common::serde::JsonObject;
+
+use matrix_goose::{
+    cli::{CliArgs, CliOption},
+    federation,
+    fixtures::User,
+    matrix::{config::SyncSettings, GooseMatrixClient, GOOSE_USERS},
+    report,
+    session::{SessionCache, SESSIONS_FILE},
+};
+
+// Totals over all users, reported at the end of the run
+#[derive(Debug, Default)]
+struct CleanupStats {
+    rooms_left: AtomicUsize,
+    invites_rejected: AtomicUsize,
+    rooms_forgotten: AtomicUsize,
+    devices_deleted: AtomicUsize,
+    accounts_deactivated: AtomicUsize,
+    failures: AtomicUsize,
+}
+
+impl CleanupStats {
+    fn add(counter: &AtomicUsize, count: usize) {
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+
+    fn summary(&self) -> String {
+        [
+            ("Rooms left", &self.rooms_left),
+            ("Invites rejected", &self.invites_rejected),
+            ("Rooms forgotten", &self.rooms_forgotten),
+            ("Devices deleted", &self.devices_deleted),
+            ("Accounts deactivated", &self.accounts_deactivated),
+            ("Failures", &self.failures),
+        ]
+        .iter()
+        .map(|(name, counter)| format!("{}: {}", name, counter.load(Ordering::Relaxed)))
+        .collect::<Vec<String>>()
+        .join("\n")
+    }
+}
+
+// Name of the only scenario, which the requests of the matrix clients are reported under
+const SCENARIO: &str = "Cleanup";
+
+static USERS: OnceCell<Vec<User>> = OnceCell::new();
+
+static SESSIONS: Lazy<SessionCache> = Lazy::new(|| SessionCache::load(SESSIONS_FILE));
+static STATS: Lazy<CleanupStats> = Lazy::new(CleanupStats::default);
+static DEACTIVATE: OnceCell<bool> = OnceCell::new();
+
+async fn setup(_user: &mut GooseUser) -> TransactionResult {
+    println!("Setting up loadtest...");
+
+    // Load users from csv
+    let mut users = Vec::new();
+    match csv::Reader::from_path("users.csv") {
+        Ok(mut reader) => {
+            for entry in reader.deserialize::<User>() {
+                match entry {
+                    Ok(record) => {
+                        // println!("{:?}", record);
+                        users.push(record);
+                    }
+                    Err(err) => panic!("Error reading user from users.csv: {}", err),
+                }
+            }
+        }
+        Err(err) => panic!("Error reading users.csv: {}", err),
+    }
+    let _ = USERS.set(users);
+
+    Ok(())
+}
+
+async fn teardown(_user: &mut GooseUser) -> TransactionResult {
+    println!("Tearing down loadtest...");
+
+    match SESSIONS.save() {
+        Ok(()) => println!("Saved {} sessions to {}", SESSIONS.len(), SESSIONS_FILE),
+        Err(err) => println!("Error writing {}: {}", SESSIONS_FILE, err),
+    }
+
+    report::add_section("Cleanup", STATS.summary());
+
+    Ok(())
+}
+
+// Builds the password stage for the UIAA session the server started
+fn password_auth(info: &UiaaInfo, username: &str, password: &str) -> Option<AuthData> {
+    let mut data = JsonObject::new();
+    data.insert(
+        "identifier".to_owned(),
+        json!({ "type": "m.id.user", "user": username }),
+    );
+    data.insert("password".to_owned(), password.into());
+
+    AuthData::new("m.login.password", info.session.to_owned(), data).ok()
+}
+
+async fn leave_rooms(client: &GooseMatrixClient, username: &str) {
+    for invite in client.invited_rooms() {
+        match invite.reject_invitation().await {
+            Ok(_) => CleanupStats::add(&STATS.invites_rejected, 1),
+            Err(err) => {
+                println!(
+                    "[{}] Could not reject invite to room {}: {:?}",
+                    username,
+                    invite.room_id(),
+                    err
+                );
+                CleanupStats::add(&STATS.failures, 1);
+            }
+        }
+    }
+
+    for joined in client.joined_rooms() {
+        match joined.leave().await {
+            Ok(_) => CleanupStats::add(&STATS.rooms_left, 1),
+            Err(err) => {
+                println!(
+                    "[{}] Could not leave room {}: {:?}",
+                    username,
+                    joined.room_id(),
+                    err
+                );
+                CleanupStats::add(&STATS.failures, 1);
+            }
+        }
+    }
+
+    // Includes the rooms that were just left or rejected
+    for left in client.left_rooms() {
+        match left.forget().await {
+            Ok(_) => CleanupStats::add(&STATS.rooms_forgotten, 1),
+            Err(err) => {
+                println!(
+                    "[{}] Could not forget room {}: {:?}",
+                    username,
+                    left.room_id(),
+                    err
+                );
+                CleanupStats::add(&STATS.failures, 1);
+            }
+        }
+    }
+}
+
+// Deletes all the devices of the user but the one the client is using
+async fn delete_other_devices(client: &GooseMatrixClient, username: &str, password: &str) {
+    let devices: Vec<_> = match client.devices().await {
+        Ok(response) => response
+            .devices
+            .into_iter()
+            .map(|device| device.device_id)
+            .filter(|device_id| Some(device_id.as_ref()) != client.device_id())
+            .collect(),
+        Err(err) => {
+            println!("[{}] Could not list devices: {:?}", username, err);
+            CleanupStats::add(&STATS.failures, 1);
+            return;
+        }
+    };
+
+    if devices.is_empty() {
+        return;
+    }
+
+    // The first request only starts the UIAA session
+    let result = match client.delete_devices(&devices, None).await {
+        Err(err) => match err.as_uiaa_response() {
+            Some(info) => {
+                let auth = password_auth(info, username, password);
+                client.delete_devices(&devices, auth).await
+            }
+            None => Err(err),
+        },
+        result => result,
+    };
+
+    match result {
+        Ok(_) => {
+            println!("[{}] Deleted {} devices", username, devices.len());
+            CleanupStats::add(&STATS.devices_deleted, devices.len());
+        }
+        Err(err) => {
+            println!("[{}] Could not delete devices: {:?}", username, err);
+            CleanupStats::add(&STATS.failures, 1);
+        }
+    }
+}
+
+async fn deactivate(client: &GooseMatrixClient, username: &str, password: &str) {
+    let account = client.account();
+
+    // The first request only starts the UIAA session
+    let result = match account.deactivate(None, None).await {
+        Err(err) => match err.as_uiaa_response() {
+            Some(info) => {
+                let auth = password_auth(info, username, password);
+                account.deactivate(None, auth).await
+            }
+            None => Err(err),
+        },
+        result => result,
+    };
+
+    match result {
+        Ok(_) => {
+            println!("[{}] Deactivated account", username);
+            SESSIONS.remove(username);
+            CleanupStats::add(&STATS.accounts_deactivated, 1);
+        }
+        Err(err) => {
+            println!("[{}] Could not deactivate account: {:?}", username, err);
+            CleanupStats::add(&STATS.failures, 1);
+        }
+    }
+}
+
+async fn cleanup(user: &mut GooseUser) -> TransactionResult {
+    let user_index = user.weighted_users_index;
+
+    let csv_user = &USERS.get().unwrap()[user_index];
+    println!(
+        "User {}: Got user/pass {} {}",
+        user_index, csv_user.username, csv_user.password
+    );
+
+    // Create matrix client
+    let username = &csv_user.username.to_owned();
+    let password = &csv_user.password.to_owned();
+    federation::set_homeserver(user, csv_user)?;
+
+    // Register the user the matrix API sends its Goose requests for
+    GOOSE_USERS.register(user, 0, SCENARIO).unwrap();
+
+    let client = federation::client(user, USERS.get().unwrap())
+        .await
+        .unwrap();
+
+    match SESSIONS.login(&client, username, password).await {
+        Ok(_) => {
+            let _ = client.sync_once(SyncSettings::default()).await;
+
+            leave_rooms(&client, username).await;
+            delete_other_devices(&client, username, password).await;
+
+            if *DEACTIVATE.get().unwrap() {
+                deactivate(&client, username, password).await;
+            }
+        }
+        Err(err) => {
+            println!("[{}] Failed login: {:?}", username, err);
+            CleanupStats::add(&STATS.failures, 1);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), GooseError> {
+    println!("Starting matrix user cleanup...");
+
+    let (configuration, args) = CliArgs::parse(&[CliOption {
+        name: "deactivate",
+        meta: "",
+        help: "Also deactivates the accounts, which cannot be undone",
+    }]);
+    DEACTIVATE.set(args.is_set("deactivate")).unwrap();
+
+    // Run test
+    GooseAttack::initialize_with_config(configuration.clone())?
+        .test_start(transaction!(setup))
+        .register_scenario(
+            scenario!(SCENARIO)
+                .register_transaction(transaction!(cleanup))
+                .set_wait_time(Duration::ZERO, Duration::ZERO)?,
+        )
+        .test_stop(transaction!(teardown))
+        .set_default(GooseDefault::HatchRate, "32")?
+        .execute()
+        .await?;
+
+    report::write(&configuration)?;
+
+    Ok(())
+}
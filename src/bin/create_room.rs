@@ -1,82 +1,98 @@
 use goose::prelude::*;
-use serde::Deserialize;
-use std::{fs::File, io::BufReader, time::Duration};
-
-use matrix_sdk::ruma::api::client::room::create_room::v3::Request as CreateRoomRequest;
-use ruma_common::{OwnedUserId, UserId};
+use once_cell::sync::{Lazy, OnceCell};
+use serde_json::{json, value::to_raw_value, Value as JsonValue};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+    sync::Mutex,
+    time::Duration,
+};
 
-use matrix_goose::matrix::{GooseMatrixClient, GOOSE_USERS};
+use matrix_sdk::ruma::api::client::{
+    membership::joined_rooms,
+    room::{
+        create_room::v3::{Request as CreateRoomRequest, RoomPreset},
+        Visibility,
+    },
+};
+use ruma_common::{
+    serde::Raw, OwnedRoomId, OwnedUserId, RoomAliasId, RoomId, RoomVersionId, ServerName,
+};
 
-#[derive(Debug, Deserialize)]
-struct User {
-    username: String,
-    password: String,
-}
+use matrix_goose::{
+    checkpoint::{Checkpoint, Outcome},
+    cli::{CliArgs, CliOption},
+    federation,
+    fixtures::{
+        self, CreatedRoom, CreatedRooms, RoomInfo, RoomKind, RoomList, User, CREATED_ROOMS_FILE,
+    },
+    matrix::{room::Joined, GOOSE_USERS},
+    report,
+    session::{SessionCache, SESSIONS_FILE},
+};
 
-#[derive(Debug, Deserialize)]
-struct RoomInfo {
-    creator: String,
-    name: String,
-    users: Vec<String>,
-}
+// Name of the only scenario, which the requests of the matrix clients are reported under
+const SCENARIO: &str = "Create Room";
 
-#[derive(Debug, Deserialize)]
-struct RoomList {
-    creators: Vec<RoomInfo>,
-}
+static USERS: OnceCell<Vec<User>> = OnceCell::new();
+static ROOMS: OnceCell<RoomList> = OnceCell::new();
 
-// For setup tests, only a single thread access its own client
-static mut USERS: Vec<User> = Vec::new();
-static USERS_READER: &Vec<User> = unsafe { &USERS };
+static CREATED_ROOMS: Lazy<Mutex<CreatedRooms>> = Lazy::new(Default::default);
+static PREVIOUS_ROOMS: OnceCell<CreatedRooms> = OnceCell::new();
+static CHECKPOINT: OnceCell<Checkpoint> = OnceCell::new();
+static SESSIONS: Lazy<SessionCache> = Lazy::new(|| SessionCache::load(SESSIONS_FILE));
+static ROOM_DEFAULTS: OnceCell<RoomDefaults> = OnceCell::new();
 
-static mut ROOMS: RoomList = RoomList {
-    creators: Vec::new(),
-};
-static ROOMS_READER: &RoomList = unsafe { &ROOMS };
+// Settings from the command line for the rooms of rooms.json that don't have their own
+#[derive(Debug)]
+struct RoomDefaults {
+    room_version: Option<RoomVersionId>,
+    preset: Option<RoomPreset>,
+    encrypted: bool,
+}
 
-async fn setup(user: &mut GooseUser) -> TransactionResult {
+async fn setup(_user: &mut GooseUser) -> TransactionResult {
     println!("Setting up loadtest...");
 
     // Load users from csv
-    unsafe {
-        let num_users = user.config.users.unwrap();
-
-        for _ in 0..num_users {
-            GOOSE_USERS.push(std::ptr::null_mut());
-        }
-
-        match csv::Reader::from_path("users.csv") {
-            Ok(mut reader) => {
-                for entry in reader.deserialize::<User>() {
-                    match entry {
-                        Ok(record) => {
-                            // println!("{:?}", record);
-                            USERS.push(record);
-                        }
-                        Err(err) => panic!("Error reading user from users.csv: {}", err),
+    let mut users = Vec::new();
+    match csv::Reader::from_path("users.csv") {
+        Ok(mut reader) => {
+            for entry in reader.deserialize::<User>() {
+                match entry {
+                    Ok(record) => {
+                        // println!("{:?}", record);
+                        users.push(record);
                     }
+                    Err(err) => panic!("Error reading user from users.csv: {}", err),
                 }
             }
-            Err(err) => panic!("Error reading users.csv: {}", err),
         }
+        Err(err) => panic!("Error reading users.csv: {}", err),
+    }
+    let _ = USERS.set(users);
 
-        // Load rooms from csv
+    // Load rooms from csv
 
-        // Open the file in read-only mode with buffer.
-        match File::open("rooms.json") {
-            Ok(file) => {
-                let reader = BufReader::new(file);
+    // Open the file in read-only mode with buffer.
+    match File::open("rooms.json") {
+        Ok(file) => {
+            let reader = BufReader::new(file);
 
-                // Read the JSON contents of the file as an instance of `User`.
-                match serde_json::from_reader::<_, RoomList>(reader) {
-                    Ok(rooms) => {
-                        ROOMS = rooms;
-                    }
-                    Err(err) => panic!("Error reading rooms.json contents: {}", err),
+            // Read the JSON contents of the file as an instance of `User`.
+            match serde_json::from_reader::<_, RoomList>(reader) {
+                Ok(rooms) => {
+                    let _ = ROOMS.set(rooms);
                 }
+                Err(err) => panic!("Error reading rooms.json contents: {}", err),
             }
-            Err(err) => panic!("Error reading rooms.json: {}", err),
         }
+        Err(err) => panic!("Error reading rooms.json: {}", err),
+    }
+
+    if let Ok(previous_rooms) = CreatedRooms::load(CREATED_ROOMS_FILE) {
+        let _ = PREVIOUS_ROOMS.set(previous_rooms);
     }
 
     Ok(())
@@ -85,14 +101,188 @@ async fn setup(user: &mut GooseUser) -> TransactionResult {
 async fn teardown(_user: &mut GooseUser) -> TransactionResult {
     println!("Tearing down loadtest...");
 
+    match SESSIONS.save() {
+        Ok(()) => println!("Saved {} sessions to {}", SESSIONS.len(), SESSIONS_FILE),
+        Err(err) => println!("Error writing {}: {}", SESSIONS_FILE, err),
+    }
+
+    // Keep the rooms created by previous runs, e.g. with a different users.csv
+    let mut created_rooms = CreatedRooms::load(CREATED_ROOMS_FILE).unwrap_or_default();
+    created_rooms
+        .rooms
+        .append(&mut CREATED_ROOMS.lock().unwrap().rooms);
+
+    match created_rooms.save(CREATED_ROOMS_FILE) {
+        Ok(()) => println!(
+            "Saved {} created rooms to {}",
+            created_rooms.rooms.len(),
+            CREATED_ROOMS_FILE
+        ),
+        Err(err) => println!("Error writing {}: {}", CREATED_ROOMS_FILE, err),
+    }
+
+    report::add_section("Rooms", CHECKPOINT.get().unwrap().summary());
+
     Ok(())
 }
 
+fn raw_json<T>(value: &JsonValue) -> Raw<T> {
+    Raw::from_json(to_raw_value(value).unwrap())
+}
+
+// Builds the request for any kind of room but DMs, which are created with `create_dm`
+fn create_room_request(room_info: &RoomInfo, invite_list: Vec<OwnedUserId>) -> CreateRoomRequest {
+    let defaults = ROOM_DEFAULTS.get().unwrap();
+
+    let mut request = CreateRoomRequest::new();
+    request.name = Some(room_info.name.to_owned());
+    request.topic = room_info.topic.to_owned();
+    request.room_alias_name = room_info.alias.to_owned();
+    request.room_version = room_info
+        .room_version
+        .to_owned()
+        .or_else(|| defaults.room_version.to_owned());
+
+    match room_info.kind {
+        RoomKind::Public => {
+            // Users find public rooms in the directory instead of being invited
+            request.preset = Some(RoomPreset::PublicChat);
+            request.visibility = Visibility::Public;
+        }
+        RoomKind::Space => {
+            let content = to_raw_value(&json!({ "type": "m.space" })).unwrap();
+            request.creation_content = Some(Raw::from_json(content));
+            request.invite = invite_list;
+        }
+        RoomKind::Dm | RoomKind::Private => {
+            request.invite = invite_list;
+        }
+    }
+
+    // A preset from the fixture wins over the one implied by the room type
+    if room_info.preset.is_some() {
+        request.preset = room_info.preset.to_owned();
+    } else if request.preset.is_none() {
+        request.preset = defaults.preset.to_owned();
+    }
+
+    if room_info.encrypted || defaults.encrypted {
+        request.initial_state.push(raw_json(&json!({
+            "type": "m.room.encryption",
+            "state_key": "",
+            "content": { "algorithm": "m.megolm.v1.aes-sha2" },
+        })));
+    }
+
+    if let Some(history_visibility) = &room_info.history_visibility {
+        request.initial_state.push(raw_json(&json!({
+            "type": "m.room.history_visibility",
+            "state_key": "",
+            "content": { "history_visibility": history_visibility },
+        })));
+    }
+
+    for event in room_info.initial_state.iter() {
+        request.initial_state.push(raw_json(event));
+    }
+
+    request.power_level_content_override = room_info.power_levels.as_ref().map(raw_json);
+
+    request
+}
+
+// Returns the ID of a room created by a previous run, from the checkpoint or
+// from rooms_created.json. Rooms the creator isn't joined to anymore, e.g. after
+// the cleanup script left them, are created again. Without the list of joined
+// rooms, the previous rooms are trusted as they are.
+fn existing_room(
+    name: &str,
+    joined_rooms: Option<&HashSet<OwnedRoomId>>,
+) -> Option<(OwnedRoomId, Outcome)> {
+    let still_joined = |room_id: &RoomId| match joined_rooms {
+        Some(joined_rooms) => joined_rooms.contains(room_id),
+        None => true,
+    };
+
+    if let Some(room_id) = CHECKPOINT.get().unwrap().get(name) {
+        if let Ok(room_id) = RoomId::parse(room_id) {
+            if still_joined(&room_id) {
+                return Some((room_id, Outcome::Resumed));
+            }
+        }
+    }
+
+    PREVIOUS_ROOMS
+        .get()
+        .and_then(|previous_rooms| previous_rooms.room_id(name))
+        .filter(|&room_id| still_joined(room_id))
+        .map(|room_id| (room_id.to_owned(), Outcome::AlreadyDone))
+}
+
+// Remembers the room so that it can be written to rooms_created.json
+fn record_created_room(
+    room_info: &RoomInfo,
+    request: &CreateRoomRequest,
+    room_id: &RoomId,
+    server_name: &ServerName,
+) {
+    let aliases = request
+        .room_alias_name
+        .iter()
+        .filter_map(|alias| RoomAliasId::parse(format!("#{}:{}", alias, server_name)).ok())
+        .collect();
+
+    CREATED_ROOMS.lock().unwrap().rooms.insert(
+        room_info.name.to_owned(),
+        CreatedRoom {
+            room_id: room_id.to_owned(),
+            aliases,
+            members: room_info.users.len() + 1,
+        },
+    );
+}
+
+// Links the children of a space to it with `m.space.child` state events
+async fn add_space_children(
+    username: &str,
+    server_name: &ServerName,
+    space: &Joined,
+    room_info: &RoomInfo,
+    created_rooms: &HashMap<&str, OwnedRoomId>,
+) {
+    for child in room_info.children.iter() {
+        let child_id = match created_rooms.get(child.as_str()) {
+            Some(child_id) => child_id,
+            None => {
+                println!(
+                    "[{}] Space {} child {} was not created by this user. Skipping...",
+                    username, room_info.name, child
+                );
+                continue;
+            }
+        };
+
+        if let Err(err) = space
+            .send_state_event_raw(
+                json!({ "via": [server_name.as_str()] }),
+                "m.space.child",
+                child_id.as_str(),
+            )
+            .await
+        {
+            println!(
+                "[{}] Could not add room {} to space {}: {:?}",
+                username, child, room_info.name, err
+            );
+        }
+    }
+}
+
 async fn create_room(user: &mut GooseUser) -> TransactionResult {
     let user_index = user.weighted_users_index;
 
     // Load the next user who needs to be registered
-    let csv_user = &USERS_READER[user_index];
+    let csv_user = &USERS.get().unwrap()[user_index];
     println!(
         "User {}: Got user/pass {} {}",
         user_index, csv_user.username, csv_user.password
@@ -101,58 +291,126 @@ async fn create_room(user: &mut GooseUser) -> TransactionResult {
     // Create matrix client
     let username = &csv_user.username.to_owned();
     let password = &csv_user.password.to_owned();
-    let host = user.base_url.to_owned();
+    federation::set_homeserver(user, csv_user)?;
 
-    // Populate static table used by matrix API for interfacing with Goose
-    unsafe { GOOSE_USERS[user_index] = user };
+    // Register the user the matrix API sends its Goose requests for
+    GOOSE_USERS.register(user, 0, SCENARIO).unwrap();
 
-    if ROOMS_READER
+    let checkpoint = CHECKPOINT.get().unwrap();
+    let mut rooms: Vec<&RoomInfo> = ROOMS
+        .get()
+        .unwrap()
         .creators
         .iter()
         .filter(|&room_info| room_info.creator == *username)
-        .count()
-        > 0
-    {
-        let client = GooseMatrixClient::new(host, user_index).await.unwrap();
+        .collect();
 
-        match client.login_username(username, password).send().await {
+    if !rooms.is_empty() {
+        let client = federation::client(user, USERS.get().unwrap())
+            .await
+            .unwrap();
+
+        match SESSIONS.login(&client, username, password).await {
             Ok(_) => {
-                let rooms_iter = ROOMS_READER
-                    .creators
-                    .iter()
-                    .filter(|&room_info| room_info.creator == *username);
+                // Spaces go last, so that their children exist by the time they are created
+                rooms.sort_by_key(|room_info| room_info.kind == RoomKind::Space);
+
+                // The server name of the user IDs, which can differ from the
+                // host of the homeserver URL, e.g. with .well-known delegation
+                let server_name = client.user_id().unwrap().server_name().to_owned();
+                let mut created_rooms: HashMap<&str, OwnedRoomId> = HashMap::new();
+
+                let joined_rooms = match client.send(joined_rooms::v3::Request::new(), None).await {
+                    Ok(response) => Some(response.joined_rooms.into_iter().collect()),
+                    Err(err) => {
+                        println!("[{}] Could not list the joined rooms: {:?}", username, err);
+                        None
+                    }
+                };
 
-                for room_info in rooms_iter {
-                    let mut request = CreateRoomRequest::new();
+                for room_info in rooms {
                     let room_name = room_info.name.to_owned();
 
                     let mut invite_list: Vec<OwnedUserId> = Vec::new();
 
                     for name in room_info.users.iter() {
-                        let host = client
-                            .homeserver()
-                            .await
-                            .host_str()
-                            .unwrap()
-                            .to_owned()
-                            .replace("matrix.", "");
-                        let full_name = "@".to_owned() + name + ":" + &host;
-                        let user_id = <&UserId>::try_from(full_name.as_str()).unwrap().to_owned();
-
-                        // println!("USER ID: {:?}", user_id);
-                        invite_list.push(user_id);
+                        match fixtures::user_id(name, &server_name) {
+                            Ok(user_id) => invite_list.push(user_id),
+                            Err(err) => println!(
+                                "[{}] Invalid user {} in room {}: {}. Skipping...",
+                                username, name, room_name, err
+                            ),
+                        }
                     }
 
-                    request.name = Some(room_name.clone());
-                    request.invite = invite_list;
+                    if room_info.kind == RoomKind::Dm && invite_list.len() != 1 {
+                        println!(
+                            "[{}] DM {} needs exactly one other user, got {}. Skipping...",
+                            username,
+                            room_name,
+                            invite_list.len()
+                        );
+                        checkpoint.record(Outcome::Failed);
+                        continue;
+                    }
+
+                    let request = create_room_request(room_info, invite_list.to_owned());
+
+                    // Reuse rooms created by a previous run instead of creating duplicates
+                    if let Some((room_id, outcome)) =
+                        existing_room(&room_info.name, joined_rooms.as_ref())
+                    {
+                        println!(
+                            "[{}] Room {} already created as {}",
+                            username, room_name, room_id
+                        );
+                        created_rooms.insert(&room_info.name, room_id.to_owned());
+                        record_created_room(room_info, &request, &room_id, &server_name);
+
+                        match outcome {
+                            Outcome::Resumed => checkpoint.record(outcome),
+                            _ => checkpoint.complete(&room_info.name, room_id.as_str(), outcome),
+                        }
+                        continue;
+                    }
 
                     let mut retries = 3;
 
                     // Send request, retry if necessary
                     while retries > 0 {
-                        match client.create_room(request.to_owned()).await {
-                            Ok(response) => {
-                                println!("[{}] Created room {}", username, response.room_id());
+                        let result = match room_info.kind {
+                            RoomKind::Dm => client.create_dm(&invite_list[0]).await,
+                            _ => client.create_room(request.to_owned()).await,
+                        };
+
+                        match result {
+                            Ok(room) => {
+                                println!("[{}] Created room {}", username, room.room_id());
+                                created_rooms.insert(&room_info.name, room.room_id().to_owned());
+                                record_created_room(
+                                    room_info,
+                                    &request,
+                                    room.room_id(),
+                                    &server_name,
+                                );
+
+                                // A space only counts as done once its children were
+                                // added, which a resumed run wouldn't do
+                                if room_info.kind == RoomKind::Space {
+                                    add_space_children(
+                                        username,
+                                        &server_name,
+                                        &room,
+                                        room_info,
+                                        &created_rooms,
+                                    )
+                                    .await;
+                                }
+                                checkpoint.complete(
+                                    &room_info.name,
+                                    room.room_id().as_str(),
+                                    Outcome::Completed,
+                                );
                                 break;
                             }
                             Err(err) => {
@@ -165,6 +423,7 @@ async fn create_room(user: &mut GooseUser) -> TransactionResult {
                                         "[{}] Error creating room {}. Skipping...",
                                         username, room_name
                                     );
+                                    checkpoint.record(Outcome::Failed);
                                     break;
                                 }
                             }
@@ -174,6 +433,9 @@ async fn create_room(user: &mut GooseUser) -> TransactionResult {
             }
             Err(err) => {
                 println!("[{}] Failed login: {:?}", username, err);
+                for _ in rooms {
+                    checkpoint.record(Outcome::Failed);
+                }
             }
         }
     }
@@ -185,11 +447,56 @@ async fn create_room(user: &mut GooseUser) -> TransactionResult {
 async fn main() -> Result<(), GooseError> {
     println!("Starting matrix user create_room loadtest...");
 
+    let (configuration, args) = CliArgs::parse(&[
+        CliOption {
+            name: "checkpoint",
+            meta: "FILE",
+            help: "Sets the file recording created rooms (default: create_room.checkpoint)",
+        },
+        CliOption {
+            name: "room-version",
+            meta: "VERSION",
+            help: "Sets the version of the rooms that don't set one",
+        },
+        CliOption {
+            name: "preset",
+            meta: "PRESET",
+            help: "Sets the preset of the private and space rooms that don't set one",
+        },
+        CliOption {
+            name: "encrypted",
+            meta: "",
+            help: "Enables encryption in all the rooms but DMs",
+        },
+    ]);
+
+    let room_version = args.get("room-version").map(|version| {
+        RoomVersionId::try_from(version)
+            .unwrap_or_else(|err| panic!("Invalid room version {}: {}", version, err))
+    });
+    let preset = args.get("preset").map(|preset| match preset {
+        "private_chat" | "public_chat" | "trusted_private_chat" => RoomPreset::from(preset),
+        _ => panic!("Unknown room preset {}", preset),
+    });
+    ROOM_DEFAULTS
+        .set(RoomDefaults {
+            room_version,
+            preset,
+            encrypted: args.is_set("encrypted"),
+        })
+        .unwrap();
+
+    let checkpoint_path = args.get("checkpoint").unwrap_or("create_room.checkpoint");
+    match Checkpoint::open(checkpoint_path) {
+        Ok(checkpoint) => CHECKPOINT.set(checkpoint).unwrap(),
+        Err(err) => panic!("Error opening checkpoint {}: {}", checkpoint_path, err),
+    }
+
     // Run test
-    GooseAttack::initialize()?
+    GooseAttack::initialize_with_config(configuration.clone())?
         .test_start(transaction!(setup))
         .register_scenario(
-            scenario!("Create Room")
+            scenario!(SCENARIO)
                 .register_transaction(transaction!(create_room))
                 .set_wait_time(Duration::ZERO, Duration::ZERO)?,
         )
@@ -198,5 +505,7 @@ async fn main() -> Result<(), GooseError> {
         .execute()
         .await?;
 
+    report::write(&configuration)?;
+
     Ok(())
 }
@@ -0,0 +1,375 @@
+use std::{collections::HashMap, fs::File, io::BufWriter, process::exit};
+
+use gumdrop::Options;
+use rand::{distributions::Alphanumeric, rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use rand_distr::{Distribution, Pareto};
+
+use matrix_goose::{
+    fixtures::{RoomInfo, RoomKind, RoomList, User},
+    rng,
+};
+
+// 80/20 rule. See also: https://en.wikipedia.org/wiki/Pareto_distribution#Relation_to_the_%22Pareto_principle%22
+const PARETO_ALPHA: f64 = 1.161;
+const DEFAULT_NUM_USERS: usize = 1000;
+
+/// Generates the users.csv and rooms.json fixtures used by the load tests
+#[derive(Debug, Options)]
+struct GenerateOptions {
+    #[options(short = "h", help = "Displays this help")]
+    help: bool,
+
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Sets the number of users to generate (default: 1000)"
+    )]
+    users: Option<usize>,
+    #[options(
+        no_short,
+        help = "Reads the users from the users file instead of generating them"
+    )]
+    keep_users: bool,
+    #[options(no_short, help = "Only generates users, without rooms")]
+    no_rooms: bool,
+    #[options(
+        no_short,
+        meta = "FILE",
+        help = "Sets the users file (default: users.csv)"
+    )]
+    users_file: Option<String>,
+    #[options(
+        no_short,
+        meta = "FILE",
+        help = "Sets the rooms file (default: rooms.json)"
+    )]
+    rooms_file: Option<String>,
+    #[options(
+        no_short,
+        meta = "SEED",
+        help = "Sets the random seed, to generate the same fixtures again"
+    )]
+    seed: Option<u64>,
+
+    #[options(
+        no_short,
+        meta = "ALPHA",
+        help = "Sets the Pareto shape of the room sizes (default: 1.161)"
+    )]
+    pareto_alpha: Option<f64>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Drops rooms with fewer members (default: 2)"
+    )]
+    min_room_size: Option<usize>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Caps the room members (default: number of users)"
+    )]
+    max_room_size: Option<usize>,
+    #[options(
+        no_short,
+        meta = "RATIO",
+        help = "Sets the number of room candidates per user (default: 1.0)"
+    )]
+    rooms_per_user: Option<f64>,
+
+    #[options(
+        no_short,
+        meta = "RATIO",
+        help = "Turns this share of the two-member rooms into DMs (default: 0.0)"
+    )]
+    dm_ratio: Option<f64>,
+    #[options(
+        no_short,
+        meta = "RATIO",
+        help = "Makes this share of the other rooms public (default: 0.0)"
+    )]
+    public_ratio: Option<f64>,
+    #[options(
+        no_short,
+        meta = "N",
+        help = "Groups the rooms of N random creators into spaces (default: 0)"
+    )]
+    spaces: Option<usize>,
+}
+
+fn generate_users(num_users: usize, rng: &mut StdRng) -> Vec<User> {
+    (0..num_users)
+        .map(|i| {
+            let username = format!("user.{:06}", i);
+            // WARNING: This is not a safe way to generate real passwords!
+            //          Here we just want a quick way to generate lots of
+            //          passwords, and anyway these are accounts that we are
+            //          going to throw away at the end of the test.
+            let password: String = (0..16).map(|_| rng.sample(Alphanumeric) as char).collect();
+            println!("username = [{}]\tpassword = [{}]", username, password);
+
+            User {
+                username,
+                password,
+                persona: None,
+                registration_token: None,
+                host: None,
+            }
+        })
+        .collect()
+}
+
+fn read_users(path: &str) -> Vec<User> {
+    match csv::Reader::from_path(path) {
+        Ok(mut reader) => reader
+            .deserialize::<User>()
+            .map(|entry| match entry {
+                Ok(record) => {
+                    println!("Found user [{}]", record.username);
+                    record
+                }
+                Err(err) => panic!("Error reading user from {}: {}", path, err),
+            })
+            .collect(),
+        Err(err) => panic!("Error reading {}: {}", path, err),
+    }
+}
+
+fn write_users(path: &str, users: &[User]) {
+    let mut writer = match csv::Writer::from_path(path) {
+        Ok(writer) => writer,
+        Err(err) => panic!("Error creating {}: {}", path, err),
+    };
+
+    for user in users {
+        if let Err(err) = writer.serialize(user) {
+            panic!("Error writing user to {}: {}", path, err);
+        }
+    }
+
+    if let Err(err) = writer.flush() {
+        panic!("Error writing {}: {}", path, err);
+    }
+}
+
+fn generate_rooms(opts: &GenerateOptions, users: &[User], rng: &mut StdRng) -> RoomList {
+    let num_users = users.len();
+    let alpha = opts.pareto_alpha.unwrap_or(PARETO_ALPHA);
+    let min_room_size = opts.min_room_size.unwrap_or(2);
+    let max_room_size = opts.max_room_size.unwrap_or(num_users).min(num_users);
+    let max_num_rooms = (num_users as f64 * opts.rooms_per_user.unwrap_or(1.0)).round() as usize;
+
+    let pareto = match Pareto::new(1.0, alpha) {
+        Ok(pareto) => pareto,
+        Err(err) => panic!("Invalid Pareto shape {}: {}", alpha, err),
+    };
+
+    // Generate a bunch of rooms with their sizes from a power law distribution
+    let mut room_sizes = Vec::new();
+    for _ in 0..max_num_rooms {
+        let size = (pareto.sample(rng).round() as usize).min(max_room_size);
+        if size < min_room_size.max(1) {
+            continue;
+        }
+        println!("s = {}", size);
+        room_sizes.push(size);
+    }
+
+    let num_rooms = room_sizes.len();
+    if num_rooms == 0 {
+        panic!(
+            "No rooms with at least {} members were generated",
+            min_room_size
+        );
+    }
+    let avg = room_sizes.iter().sum::<usize>() as f64 / num_rooms as f64;
+
+    println!("###################################");
+    println!("{} Total rooms", num_rooms);
+    println!("Max = {}", room_sizes.iter().max().unwrap());
+    println!("Min = {}", room_sizes.iter().min().unwrap());
+    println!("Avg = {}", avg);
+    println!("###################################");
+
+    // Now assign the users (randomly) to the slots in the rooms, the first one
+    // creates the room and invites the others
+    let creators = room_sizes
+        .iter()
+        .enumerate()
+        .map(|(i, size)| {
+            let mut members: Vec<&User> = users.choose_multiple(rng, *size).collect();
+            members.shuffle(rng);
+
+            RoomInfo {
+                creator: members[0].username.to_owned(),
+                name: format!("Room {}", i),
+                users: members[1..]
+                    .iter()
+                    .map(|member| member.username.to_owned())
+                    .collect(),
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    let mut rooms = RoomList { creators };
+    assign_room_kinds(opts, &mut rooms, rng);
+    rooms
+}
+
+fn check_ratio(name: &str, ratio: Option<f64>) -> f64 {
+    match ratio {
+        Some(ratio) if !(0.0..=1.0).contains(&ratio) => {
+            panic!("--{} must be between 0 and 1, got {}", name, ratio)
+        }
+        Some(ratio) => ratio,
+        None => 0.0,
+    }
+}
+
+// Turns some of the generated rooms into DMs, public rooms and spaces
+fn assign_room_kinds(opts: &GenerateOptions, rooms: &mut RoomList, rng: &mut StdRng) {
+    let dm_ratio = check_ratio("dm-ratio", opts.dm_ratio);
+    let public_ratio = check_ratio("public-ratio", opts.public_ratio);
+
+    for room in rooms.creators.iter_mut() {
+        if room.users.len() == 1 && dm_ratio > 0.0 && rng.gen_bool(dm_ratio) {
+            room.kind = RoomKind::Dm;
+        } else if public_ratio > 0.0 && rng.gen_bool(public_ratio) {
+            room.kind = RoomKind::Public;
+        }
+    }
+
+    // Each space holds all the non-DM rooms of a random creator, whose members
+    // are invited to the space
+    let mut space_creators: Vec<String> = rooms
+        .creators
+        .iter()
+        .filter(|room| room.kind != RoomKind::Dm)
+        .map(|room| room.creator.to_owned())
+        .collect();
+    space_creators.sort();
+    space_creators.dedup();
+
+    let num_spaces = opts.spaces.unwrap_or(0).min(space_creators.len());
+    let space_creators: Vec<String> = space_creators
+        .choose_multiple(rng, num_spaces)
+        .cloned()
+        .collect();
+
+    for (i, creator) in space_creators.into_iter().enumerate() {
+        let children: Vec<&RoomInfo> = rooms
+            .creators
+            .iter()
+            .filter(|room| room.creator == creator && room.kind != RoomKind::Dm)
+            .collect();
+
+        let mut users: Vec<String> = children
+            .iter()
+            .flat_map(|room| room.users.iter().cloned())
+            .collect();
+        users.sort();
+        users.dedup();
+
+        let space = RoomInfo {
+            creator,
+            name: format!("Space {}", i),
+            users,
+            kind: RoomKind::Space,
+            children: children.iter().map(|room| room.name.to_owned()).collect(),
+            ..Default::default()
+        };
+        rooms.creators.push(space);
+    }
+
+    let count = |kind| {
+        rooms
+            .creators
+            .iter()
+            .filter(|room| room.kind == kind)
+            .count()
+    };
+    println!(
+        "{} DMs, {} private rooms, {} public rooms, {} spaces",
+        count(RoomKind::Dm),
+        count(RoomKind::Private),
+        count(RoomKind::Public),
+        count(RoomKind::Space)
+    );
+}
+
+fn write_rooms(path: &str, rooms: &RoomList) {
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => panic!("Error creating {}: {}", path, err),
+    };
+
+    if let Err(err) = serde_json::to_writer(BufWriter::new(file), rooms) {
+        panic!("Error writing {}: {}", path, err);
+    }
+}
+
+// Analyze the set of room assignments from the users' point of view
+fn print_assignments(users: &[User], rooms: &RoomList) {
+    let mut assignments: HashMap<&str, usize> = HashMap::new();
+    for room in &rooms.creators {
+        for member in std::iter::once(&room.creator).chain(room.users.iter()) {
+            *assignments.entry(member.as_str()).or_default() += 1;
+        }
+    }
+
+    let num_rooms = rooms.creators.len();
+    let counts: Vec<usize> = users
+        .iter()
+        .map(|user| {
+            assignments
+                .get(user.username.as_str())
+                .copied()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    println!(
+        "{} users in zero rooms",
+        counts.iter().filter(|count| **count < 1).count()
+    );
+    println!(
+        "{} users in all rooms",
+        counts.iter().filter(|count| **count == num_rooms).count()
+    );
+    println!(
+        "{} users in > 100 rooms",
+        counts.iter().filter(|count| **count > 99).count()
+    );
+}
+
+fn main() {
+    let opts = GenerateOptions::parse_args_default_or_exit();
+
+    if opts.keep_users && opts.no_rooms {
+        eprintln!("--keep-users and --no-rooms together leave nothing to generate");
+        exit(2);
+    }
+
+    let users_file = opts.users_file.as_deref().unwrap_or("users.csv");
+    let rooms_file = opts.rooms_file.as_deref().unwrap_or("rooms.json");
+
+    let seed = rng::set_seed(opts.seed);
+    println!("Using random seed {}", seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let users = if opts.keep_users {
+        read_users(users_file)
+    } else {
+        let users = generate_users(opts.users.unwrap_or(DEFAULT_NUM_USERS), &mut rng);
+        write_users(users_file, &users);
+        users
+    };
+
+    if opts.no_rooms {
+        return;
+    }
+
+    let rooms = generate_rooms(&opts, &users, &mut rng);
+    write_rooms(rooms_file, &rooms);
+    print_assignments(&users, &rooms);
+}
@@ -1,43 +1,77 @@
 use goose::prelude::*;
-use std::time::Duration;
+use once_cell::sync::{Lazy, OnceCell};
+use std::{collections::HashSet, fs::File, io::BufReader, sync::Mutex, time::Duration};
 
-use matrix_goose::matrix::{config::SyncSettings, GooseMatrixClient, GOOSE_USERS};
+use matrix_sdk::ruma::{
+    api::client::directory::get_public_rooms_filtered, directory::Filter, uint, OwnedRoomId,
+    OwnedServerName, UserId,
+};
 
-#[derive(Debug, serde::Deserialize)]
-struct User {
-    username: String,
-    password: String,
-}
+use matrix_goose::{
+    checkpoint::{Checkpoint, Outcome},
+    cli::{CliArgs, CliOption},
+    federation,
+    fixtures::{CreatedRooms, RoomKind, RoomList, User, CREATED_ROOMS_FILE},
+    matrix::{config::SyncSettings, GooseMatrixClient, HttpResult, GOOSE_USERS},
+    report,
+    session::{SessionCache, SESSIONS_FILE},
+};
 
-// For setup tests, only a single thread access its own client
-static mut USERS: Vec<User> = Vec::new();
-static USERS_READER: &Vec<User> = unsafe { &USERS };
+// Name of the only scenario, which the requests of the matrix clients are reported under
+const SCENARIO: &str = "Join";
 
-async fn setup(user: &mut GooseUser) -> TransactionResult {
-    println!("Setting up loadtest...");
+static USERS: OnceCell<Vec<User>> = OnceCell::new();
+static ROOMS: OnceCell<RoomList> = OnceCell::new();
 
-    // Load users from csv
-    unsafe {
-        let num_users = user.config.users.unwrap();
+static CREATED_ROOMS: OnceCell<CreatedRooms> = OnceCell::new();
+// User ID, room name and room ID of the memberships from rooms_created.json
+// which are missing after joining
+static MISSING_MEMBERSHIPS: Lazy<Mutex<Vec<(String, String, OwnedRoomId)>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+static CHECKPOINT: OnceCell<Checkpoint> = OnceCell::new();
+static SESSIONS: Lazy<SessionCache> = Lazy::new(|| SessionCache::load(SESSIONS_FILE));
 
-        for _ in 0..num_users {
-            GOOSE_USERS.push(std::ptr::null_mut());
-        }
+async fn setup(_user: &mut GooseUser) -> TransactionResult {
+    println!("Setting up loadtest...");
 
-        match csv::Reader::from_path("users.csv") {
-            Ok(mut reader) => {
-                for entry in reader.deserialize::<User>() {
-                    match entry {
-                        Ok(record) => {
-                            // println!("{:?}", record);
-                            USERS.push(record);
-                        }
-                        Err(err) => panic!("Error reading user from users.csv: {}", err),
+    // Load users from csv
+    let mut users = Vec::new();
+    match csv::Reader::from_path("users.csv") {
+        Ok(mut reader) => {
+            for entry in reader.deserialize::<User>() {
+                match entry {
+                    Ok(record) => {
+                        // println!("{:?}", record);
+                        users.push(record);
                     }
+                    Err(err) => panic!("Error reading user from users.csv: {}", err),
                 }
             }
-            Err(err) => panic!("Error reading users.csv: {}", err),
         }
+        Err(err) => panic!("Error reading users.csv: {}", err),
+    }
+    let _ = USERS.set(users);
+
+    // Load rooms, to find the public rooms to join
+    match File::open("rooms.json") {
+        Ok(file) => match serde_json::from_reader::<_, RoomList>(BufReader::new(file)) {
+            Ok(rooms) => {
+                let _ = ROOMS.set(rooms);
+            }
+            Err(err) => panic!("Error reading rooms.json contents: {}", err),
+        },
+        Err(err) => panic!("Error reading rooms.json: {}", err),
+    }
+
+    // Only needed to verify the memberships, so it is fine if create_room wasn't run
+    match CreatedRooms::load(CREATED_ROOMS_FILE) {
+        Ok(created_rooms) => {
+            let _ = CREATED_ROOMS.set(created_rooms);
+        }
+        Err(err) => println!(
+            "Not verifying room memberships, could not read {}: {}",
+            CREATED_ROOMS_FILE, err
+        ),
     }
 
     Ok(())
@@ -46,14 +80,191 @@ async fn setup(user: &mut GooseUser) -> TransactionResult {
 async fn teardown(_user: &mut GooseUser) -> TransactionResult {
     println!("Tearing down loadtest...");
 
+    match SESSIONS.save() {
+        Ok(()) => println!("Saved {} sessions to {}", SESSIONS.len(), SESSIONS_FILE),
+        Err(err) => println!("Error writing {}: {}", SESSIONS_FILE, err),
+    }
+
+    report::add_section("Joins", CHECKPOINT.get().unwrap().summary());
+
+    if CREATED_ROOMS.get().is_some() {
+        let summary = missing_memberships_summary();
+        println!("{}", summary);
+        report::add_section("Missing memberships", summary);
+    }
+
     Ok(())
 }
 
+// Joins the public rooms listing the user, found by name in the room directory.
+// Returns how many rooms were joined and how many failed.
+async fn join_public_rooms(client: &GooseMatrixClient, username: &str) -> (usize, usize) {
+    let mut joined = 0;
+    let mut failed = 0;
+
+    let rooms_iter = ROOMS.get().unwrap().creators.iter().filter(|&room_info| {
+        room_info.kind == RoomKind::Public && room_info.users.iter().any(|name| name == username)
+    });
+
+    for room_info in rooms_iter {
+        // Skip the directory search for rooms a previous run already joined
+        let known_room_id = CREATED_ROOMS
+            .get()
+            .and_then(|created_rooms| created_rooms.room_id(&room_info.name));
+        if known_room_id.map_or(false, |room_id| client.get_joined_room(room_id).is_some()) {
+            continue;
+        }
+
+        // Public rooms of users on other homeservers are listed in their directory
+        let mut server = None;
+        if let Ok(creator) = UserId::parse(&room_info.creator) {
+            if client.user_id().map(UserId::server_name) != Some(creator.server_name()) {
+                server = Some(creator.server_name().to_owned());
+            }
+        }
+
+        let room_id = match find_public_room(client, &room_info.name, server).await {
+            Ok(room_id) => room_id,
+            Err(err) => {
+                println!(
+                    "[{}] Could not search the directory for room {}: {:?}",
+                    username, room_info.name, err
+                );
+                failed += 1;
+                continue;
+            }
+        };
+
+        match room_id {
+            Some(room_id) if client.get_joined_room(&room_id).is_some() => {}
+            Some(room_id) => match client.join_room_by_id(&room_id).await {
+                Ok(response) => {
+                    println!("[{}] Joined public room {}", username, response.room_id());
+                    joined += 1;
+                }
+                Err(err) => {
+                    println!(
+                        "[{}] Could not join public room {}: {:?}",
+                        username, room_info.name, err
+                    );
+                    failed += 1;
+                }
+            },
+            None => {
+                println!(
+                    "[{}] Public room {} is not in the directory. Skipping...",
+                    username, room_info.name
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    (joined, failed)
+}
+
+// Searches the room directory for the public room with the given name. The
+// search matches any room whose name contains the search term, e.g. "Room 10"
+// to "Room 199" for "Room 1", so the results are paged through until the name
+// matches exactly.
+async fn find_public_room(
+    client: &GooseMatrixClient,
+    name: &str,
+    server: Option<OwnedServerName>,
+) -> HttpResult<Option<OwnedRoomId>> {
+    let mut since = None;
+
+    loop {
+        let mut filter = Filter::new();
+        filter.generic_search_term = Some(name.to_owned());
+        let mut request = get_public_rooms_filtered::v3::Request::new();
+        request.filter = filter;
+        request.server = server.clone();
+        request.limit = Some(uint!(100));
+        request.since = since;
+
+        let response = client.public_rooms_filtered(request).await?;
+        let room = response
+            .chunk
+            .into_iter()
+            .find(|room| room.name.as_deref() == Some(name));
+        if let Some(room) = room {
+            return Ok(Some(room.room_id));
+        }
+
+        match response.next_batch {
+            Some(next_batch) => since = Some(next_batch),
+            None => return Ok(None),
+        }
+    }
+}
+
+// Checks that the user ended up in every created room rooms.json lists it in
+fn verify_memberships(client: &GooseMatrixClient, username: &str) {
+    let created_rooms = match CREATED_ROOMS.get() {
+        Some(created_rooms) => created_rooms,
+        None => return,
+    };
+
+    let rooms_iter = ROOMS.get().unwrap().creators.iter().filter(|&room_info| {
+        room_info.creator == username || room_info.users.iter().any(|name| name == username)
+    });
+
+    for room_info in rooms_iter {
+        let room_id = match created_rooms.room_id(&room_info.name) {
+            Some(room_id) => room_id,
+            // Rooms that failed to be created are already reported by create_room
+            None => continue,
+        };
+
+        if client.get_joined_room(room_id).is_none() {
+            println!(
+                "[{}] Missing from room {} ({})",
+                username, room_info.name, room_id
+            );
+            let user_id = client
+                .user_id()
+                .map_or_else(|| username.to_owned(), |user_id| user_id.to_string());
+            MISSING_MEMBERSHIPS.lock().unwrap().push((
+                user_id,
+                room_info.name.to_owned(),
+                room_id.to_owned(),
+            ));
+        }
+    }
+}
+
+// Counts the missing memberships and the users and rooms they affect, followed
+// by one line per membership
+fn missing_memberships_summary() -> String {
+    let missing = MISSING_MEMBERSHIPS.lock().unwrap();
+    if missing.is_empty() {
+        return "No room memberships from rooms_created.json are missing".to_owned();
+    }
+
+    let users: HashSet<&str> = missing
+        .iter()
+        .map(|(user_id, ..)| user_id.as_str())
+        .collect();
+    let rooms: HashSet<&OwnedRoomId> = missing.iter().map(|(_, _, room_id)| room_id).collect();
+    let mut lines = vec![
+        format!("Missing memberships: {}", missing.len()),
+        format!("Users affected: {}", users.len()),
+        format!("Rooms affected: {}", rooms.len()),
+        String::new(),
+    ];
+    for (user_id, room_name, room_id) in missing.iter() {
+        lines.push(format!("{} in {} ({})", user_id, room_name, room_id));
+    }
+
+    lines.join("\n")
+}
+
 async fn join(user: &mut GooseUser) -> TransactionResult {
     let user_index = user.weighted_users_index;
 
     // Load the next user who needs to be registered
-    let csv_user = &USERS_READER[user_index];
+    let csv_user = &USERS.get().unwrap()[user_index];
     println!(
         "User {}: Got user/pass {} {}",
         user_index, csv_user.username, csv_user.password
@@ -62,14 +273,23 @@ async fn join(user: &mut GooseUser) -> TransactionResult {
     // Create matrix client
     let username = &csv_user.username.to_owned();
     let password = &csv_user.password.to_owned();
-    let host = user.base_url.to_owned();
+    federation::set_homeserver(user, csv_user)?;
+
+    let checkpoint = CHECKPOINT.get().unwrap();
+    if checkpoint.is_done(username) {
+        println!("[{}] Already joined its rooms in a previous run", username);
+        checkpoint.record(Outcome::Resumed);
+        return Ok(());
+    }
 
-    // Populate static table used by matrix API for interfacing with Goose
-    unsafe { GOOSE_USERS[user_index] = user };
+    // Register the user the matrix API sends its Goose requests for
+    GOOSE_USERS.register(user, 0, SCENARIO).unwrap();
 
-    let client = GooseMatrixClient::new(host, user_index).await.unwrap();
+    let client = federation::client(user, USERS.get().unwrap())
+        .await
+        .unwrap();
 
-    match client.login_username(username, password).send().await {
+    match SESSIONS.login(&client, username, password).await {
         Ok(_) => {
             let _ = client.sync_once(SyncSettings::default()).await;
             println!(
@@ -78,14 +298,19 @@ async fn join(user: &mut GooseUser) -> TransactionResult {
                 client.invited_rooms().len()
             );
 
+            let mut joined = 0;
+            let mut failed = 0;
+
             for invite in client.invited_rooms() {
                 let mut retries = 3;
 
                 // Send request, retry if necessary
                 while retries > 0 {
-                    match client.join_room_by_id(invite.room_id()).await {
+                    // Accepting marks DMs as such in the user's account data
+                    match invite.accept_invitation().await {
                         Ok(response) => {
                             println!("[{}] Joined room {}", username, response.room_id());
+                            joined += 1;
                             break;
                         }
                         Err(err) => {
@@ -104,15 +329,31 @@ async fn join(user: &mut GooseUser) -> TransactionResult {
                                     username,
                                     invite.room_id()
                                 );
+                                failed += 1;
                                 break;
                             }
                         }
                     }
                 }
             }
+
+            let (public_joined, public_failed) = join_public_rooms(&client, username).await;
+            joined += public_joined;
+            failed += public_failed;
+            verify_memberships(&client, username);
+
+            // Users with failures aren't checkpointed, so the next run tries them again
+            if failed > 0 {
+                checkpoint.record(Outcome::Failed);
+            } else if joined > 0 {
+                checkpoint.complete(username, "", Outcome::Completed);
+            } else {
+                checkpoint.complete(username, "", Outcome::AlreadyDone);
+            }
         }
         Err(err) => {
             println!("[{}] Failed login: {:?}", username, err);
+            checkpoint.record(Outcome::Failed);
         }
     }
 
@@ -123,11 +364,23 @@ async fn join(user: &mut GooseUser) -> TransactionResult {
 async fn main() -> Result<(), GooseError> {
     println!("Starting matrix user join loadtest...");
 
+    let (configuration, args) = CliArgs::parse(&[CliOption {
+        name: "checkpoint",
+        meta: "FILE",
+        help: "Sets the file recording users done joining (default: join.checkpoint)",
+    }]);
+
+    let checkpoint_path = args.get("checkpoint").unwrap_or("join.checkpoint");
+    match Checkpoint::open(checkpoint_path) {
+        Ok(checkpoint) => CHECKPOINT.set(checkpoint).unwrap(),
+        Err(err) => panic!("Error opening checkpoint {}: {}", checkpoint_path, err),
+    }
+
     // Run test
-    GooseAttack::initialize()?
+    GooseAttack::initialize_with_config(configuration.clone())?
         .test_start(transaction!(setup))
         .register_scenario(
-            scenario!("Join")
+            scenario!(SCENARIO)
                 .register_transaction(transaction!(join))
                 .set_wait_time(Duration::ZERO, Duration::ZERO)?,
         )
@@ -136,5 +389,7 @@ async fn main() -> Result<(), GooseError> {
         .execute()
         .await?;
 
+    report::write(&configuration)?;
+
     Ok(())
 }
@@ -1,47 +1,69 @@
 use goose::prelude::*;
-use ruma::api::client::uiaa::Dummy;
+use once_cell::sync::{Lazy, OnceCell};
 use std::time::Duration;
 
-use matrix_sdk::ruma::api::client::{account::register::v3::Request as RegistrationRequest, uiaa};
+use matrix_sdk::ruma::api::client::{
+    account::register::v3::Request as RegistrationRequest,
+    error::ErrorKind,
+    uiaa::{AuthData, Dummy, UiaaInfo},
+};
+use ruma_common::{serde::JsonObject, OwnedDeviceId, UserId};
 
-use matrix_goose::matrix::{GooseMatrixClient, GOOSE_USERS};
+use matrix_goose::{
+    admin,
+    checkpoint::{Checkpoint, Outcome},
+    cli::{CliArgs, CliOption},
+    federation,
+    fixtures::{self, User},
+    matrix::{with_expected_errcodes, Session, GOOSE_USERS},
+    report,
+    session::{SessionCache, SESSIONS_FILE},
+};
 
-#[derive(Debug, serde::Deserialize)]
-struct User {
-    username: String,
-    password: String,
+/// How users are registered.
+#[derive(Debug)]
+enum RegistrationMode {
+    /// Client-server API registration, completing the supported UIAA stages
+    Open,
+    /// Synapse admin API registration with the server's shared secret
+    SharedSecret(String),
 }
 
-// For setup tests, only a single thread access its own client
-static mut USERS: Vec<User> = Vec::new();
-static USERS_READER: &Vec<User> = unsafe { &USERS };
+// Name of the only scenario, which the requests of the matrix clients are reported under
+const SCENARIO: &str = "Register";
 
-async fn setup(user: &mut GooseUser) -> TransactionResult {
-    println!("Setting up loadtest...");
+static USERS: OnceCell<Vec<User>> = OnceCell::new();
 
-    // Load users from csv
-    unsafe {
-        let num_users = user.config.users.unwrap();
+static SESSIONS: Lazy<SessionCache> = Lazy::new(|| SessionCache::load(SESSIONS_FILE));
+static REGISTRATION: OnceCell<RegistrationMode> = OnceCell::new();
+static REGISTRATION_TOKEN: OnceCell<String> = OnceCell::new();
+static CHECKPOINT: OnceCell<Checkpoint> = OnceCell::new();
 
-        for _ in 0..num_users {
-            GOOSE_USERS.push(std::ptr::null_mut());
-        }
+const REGISTRATION_TOKEN_STAGES: [&str; 2] = [
+    "m.login.registration_token",
+    "org.matrix.msc3231.login.registration_token",
+];
 
-        match csv::Reader::from_path("users.csv") {
-            Ok(mut reader) => {
-                for entry in reader.deserialize::<User>() {
-                    match entry {
-                        Ok(record) => {
-                            // println!("{:?}", record);
-                            USERS.push(record);
-                        }
-                        Err(err) => panic!("Error reading user from users.csv: {}", err),
+async fn setup(_user: &mut GooseUser) -> TransactionResult {
+    println!("Setting up loadtest...");
+
+    // Load users from csv
+    let mut users = Vec::new();
+    match csv::Reader::from_path("users.csv") {
+        Ok(mut reader) => {
+            for entry in reader.deserialize::<User>() {
+                match entry {
+                    Ok(record) => {
+                        // println!("{:?}", record);
+                        users.push(record);
                     }
+                    Err(err) => panic!("Error reading user from users.csv: {}", err),
                 }
             }
-            Err(err) => panic!("Error reading users.csv: {}", err),
         }
+        Err(err) => panic!("Error reading users.csv: {}", err),
     }
+    let _ = USERS.set(users);
 
     Ok(())
 }
@@ -49,6 +71,13 @@ async fn setup(user: &mut GooseUser) -> TransactionResult {
 async fn teardown(_user: &mut GooseUser) -> TransactionResult {
     println!("Tearing down loadtest...");
 
+    match SESSIONS.save() {
+        Ok(()) => println!("Saved {} sessions to {}", SESSIONS.len(), SESSIONS_FILE),
+        Err(err) => println!("Error writing {}: {}", SESSIONS_FILE, err),
+    }
+
+    report::add_section("Registrations", CHECKPOINT.get().unwrap().summary());
+
     Ok(())
 }
 
@@ -56,7 +85,7 @@ async fn register(user: &mut GooseUser) -> TransactionResult {
     let user_index = user.weighted_users_index;
 
     // Load the next user who needs to be registered
-    let csv_user = &USERS_READER[user_index];
+    let csv_user = &USERS.get().unwrap()[user_index];
     println!(
         "User {}: Got user/pass {} {}",
         user_index, csv_user.username, csv_user.password
@@ -65,26 +94,98 @@ async fn register(user: &mut GooseUser) -> TransactionResult {
     // Create matrix client
     let username = csv_user.username.to_owned();
     let password = csv_user.password.to_owned();
-    let host = user.base_url.to_owned();
+    federation::set_homeserver(user, csv_user)?;
+    let checkpoint = CHECKPOINT.get().unwrap();
+
+    if checkpoint.is_done(&username) {
+        println!("[{}] Already registered by a previous run", username);
+        checkpoint.record(Outcome::Resumed);
+        return Ok(());
+    }
+
+    if let Some(RegistrationMode::SharedSecret(shared_secret)) = REGISTRATION.get() {
+        return register_with_shared_secret(user, shared_secret, &username, &password).await;
+    }
+
+    // Register the user the matrix API sends its Goose requests for
+    GOOSE_USERS.register(user, 0, SCENARIO).unwrap();
+    let client = federation::client(user, USERS.get().unwrap())
+        .await
+        .unwrap();
 
-    // Populate static table used by matrix API for interfacing with Goose
-    unsafe { GOOSE_USERS[user_index] = user };
     let mut request = RegistrationRequest::new();
 
-    request.username = Some(username.to_owned());
+    request.username = Some(csv_user.localpart().to_owned());
     request.password = Some(password.to_owned());
-    request.auth = Some(uiaa::AuthData::Dummy(Dummy::new()));
 
-    let client = GooseMatrixClient::new(host, user_index).await.unwrap();
+    // The token from users.csv takes precedence over the one from the command line
+    let registration_token = csv_user
+        .registration_token
+        .as_deref()
+        .or_else(|| REGISTRATION_TOKEN.get().map(String::as_str));
+
+    // Most servers only ask for the dummy stage, which completes the registration
+    // in a single request. Servers asking for other stages answer with their UIAA
+    // flows, which are then completed stage by stage.
+    request.auth = Some(AuthData::Dummy(Dummy::new()));
+
     let mut retries = 3;
 
     // Send request, retry if necessary
     while retries > 0 {
-        match client.register(request.to_owned()).await {
-            Ok(_) => {
+        // Existing users are skipped rather than failed, see below
+        let result =
+            with_expected_errcodes(&["M_USER_IN_USE"], client.register(request.to_owned())).await;
+        match result {
+            Ok(response) => {
                 println!("[{}] User registration success", username);
+                SESSIONS.insert_registered(&username, &response);
+                checkpoint.complete(&username, "", Outcome::Completed);
+                return Ok(());
+            }
+            Err(err) if matches!(err.client_api_error_kind(), Some(ErrorKind::UserInUse)) => {
+                println!("[{}] User already exists", username);
+                checkpoint.complete(&username, "", Outcome::AlreadyDone);
                 return Ok(());
             }
+            Err(err) if err.as_uiaa_response().is_some() => {
+                let info = err.as_uiaa_response().unwrap();
+
+                if let Some(auth_error) = &info.auth_error {
+                    println!(
+                        "[{}] Registration stage failed (attempt {}): {}. Trying again...",
+                        username,
+                        4 - retries,
+                        auth_error.message
+                    );
+                    request.auth = Some(AuthData::Dummy(Dummy::new()));
+                    retries -= 1;
+                    continue;
+                }
+
+                match next_auth_stage(info, registration_token) {
+                    // The server didn't accept the stage we just sent, start over
+                    Ok(auth)
+                        if request.auth.as_ref().map(AuthData::auth_type)
+                            == Some(auth.auth_type()) =>
+                    {
+                        println!(
+                            "[{}] Registration stage {:?} not accepted (attempt {}). Trying again...",
+                            username,
+                            auth.auth_type(),
+                            4 - retries
+                        );
+                        request.auth = Some(AuthData::Dummy(Dummy::new()));
+                        retries -= 1;
+                    }
+                    Ok(auth) => request.auth = Some(auth),
+                    Err(message) => {
+                        println!("[{}] Cannot register user: {}", username, message);
+                        checkpoint.record(Outcome::Failed);
+                        return Ok(());
+                    }
+                }
+            }
             Err(err) => {
                 println!(
                     "[{}] Could not register user (attempt {}): {:?}. Trying again...",
@@ -92,12 +193,125 @@ async fn register(user: &mut GooseUser) -> TransactionResult {
                     4 - retries,
                     err
                 );
+                request.auth = Some(AuthData::Dummy(Dummy::new()));
+                retries -= 1;
+            }
+        }
+    }
+
+    println!("Error registering user {}. Skipping...", username);
+    CHECKPOINT.get().unwrap().record(Outcome::Failed);
+
+    Ok(())
+}
+
+fn is_supported_stage(stage: &str, registration_token: Option<&str>) -> bool {
+    match stage {
+        "m.login.dummy" | "m.login.terms" => true,
+        _ => REGISTRATION_TOKEN_STAGES.contains(&stage) && registration_token.is_some(),
+    }
+}
+
+// Picks the first UIAA flow whose stages we can all complete, and returns the
+// auth data for its next stage
+fn next_auth_stage(info: &UiaaInfo, registration_token: Option<&str>) -> Result<AuthData, String> {
+    let flow = info
+        .flows
+        .iter()
+        .find(|flow| {
+            flow.stages
+                .iter()
+                .all(|stage| is_supported_stage(stage.as_str(), registration_token))
+        })
+        .ok_or_else(|| {
+            let flows: Vec<Vec<&str>> = info
+                .flows
+                .iter()
+                .map(|flow| flow.stages.iter().map(|stage| stage.as_str()).collect())
+                .collect();
+            format!("no supported registration flow in {:?}", flows)
+        })?;
+
+    let stage = flow
+        .stages
+        .iter()
+        .find(|stage| !info.completed.contains(stage))
+        .ok_or_else(|| "server asks for more auth after all stages completed".to_owned())?;
+
+    // Accepting the terms and the dummy stage don't need any data
+    let mut data = JsonObject::new();
+    if REGISTRATION_TOKEN_STAGES.contains(&stage.as_str()) {
+        data.insert("token".to_owned(), registration_token.unwrap().into());
+    }
+
+    AuthData::new(stage.as_str(), info.session.to_owned(), data).map_err(|err| err.to_string())
+}
+
+async fn register_with_shared_secret(
+    user: &mut GooseUser,
+    shared_secret: &str,
+    username: &str,
+    password: &str,
+) -> TransactionResult {
+    let mut retries = 3;
+
+    // Send request, retry if necessary
+    while retries > 0 {
+        // Existing users are skipped rather than failed, like in open registration
+        let result = with_expected_errcodes(
+            &["M_USER_IN_USE"],
+            admin::register_with_shared_secret(
+                user,
+                shared_secret,
+                fixtures::localpart(username),
+                password,
+                false,
+            ),
+        )
+        .await;
+        match result {
+            Ok(registration) => {
+                println!("[{}] User registration success", username);
+
+                if let Ok(user_id) = UserId::parse(&registration.user_id) {
+                    SESSIONS.insert(
+                        username,
+                        Session {
+                            access_token: registration.access_token,
+                            refresh_token: None,
+                            user_id,
+                            device_id: OwnedDeviceId::from(registration.device_id),
+                        },
+                    );
+                }
+                CHECKPOINT
+                    .get()
+                    .unwrap()
+                    .complete(username, "", Outcome::Completed);
+                return Ok(());
+            }
+            Err(err) if err.errcode().as_deref() == Some("M_USER_IN_USE") => {
+                println!("[{}] User already exists", username);
+                CHECKPOINT
+                    .get()
+                    .unwrap()
+                    .complete(username, "", Outcome::AlreadyDone);
+                return Ok(());
+            }
+            Err(err) => {
+                println!(
+                    "[{}] Could not register user (attempt {}): {}. Trying again...",
+                    username,
+                    4 - retries,
+                    err
+                );
                 retries -= 1;
             }
         }
     }
 
     println!("Error registering user {}. Skipping...", username);
+    CHECKPOINT.get().unwrap().record(Outcome::Failed);
 
     Ok(())
 }
@@ -106,11 +320,63 @@ async fn register(user: &mut GooseUser) -> TransactionResult {
 async fn main() -> Result<(), GooseError> {
     println!("Starting matrix user register loadtest...");
 
+    let (configuration, args) = CliArgs::parse(&[
+        CliOption {
+            name: "checkpoint",
+            meta: "FILE",
+            help: "Sets the file recording registered users (default: register.checkpoint)",
+        },
+        CliOption {
+            name: "registration",
+            meta: "MODE",
+            help: "Sets how users are registered: open (default) or shared-secret",
+        },
+        CliOption {
+            name: "registration-token",
+            meta: "TOKEN",
+            help: "Sets the token for servers requiring m.login.registration_token",
+        },
+        CliOption {
+            name: "shared-secret",
+            meta: "SECRET",
+            help: "Sets the registration shared secret, defaults to $REGISTRATION_SHARED_SECRET",
+        },
+    ]);
+
+    let mode = match args.get("registration").unwrap_or("open") {
+        "open" => RegistrationMode::Open,
+        "shared-secret" => {
+            let shared_secret = args
+                .get("shared-secret")
+                .map(str::to_owned)
+                .or_else(|| std::env::var("REGISTRATION_SHARED_SECRET").ok());
+
+            match shared_secret {
+                Some(shared_secret) => RegistrationMode::SharedSecret(shared_secret),
+                None => panic!("--registration shared-secret requires --shared-secret"),
+            }
+        }
+        mode => panic!("Unknown registration mode '{}'", mode),
+    };
+    REGISTRATION.set(mode).unwrap();
+
+    if let Some(registration_token) = args.get("registration-token") {
+        REGISTRATION_TOKEN
+            .set(registration_token.to_owned())
+            .unwrap();
+    }
+
+    let checkpoint_path = args.get("checkpoint").unwrap_or("register.checkpoint");
+    match Checkpoint::open(checkpoint_path) {
+        Ok(checkpoint) => CHECKPOINT.set(checkpoint).unwrap(),
+        Err(err) => panic!("Error opening checkpoint {}: {}", checkpoint_path, err),
+    }
+
     // Run test
-    GooseAttack::initialize()?
+    GooseAttack::initialize_with_config(configuration.clone())?
         .test_start(transaction!(setup))
         .register_scenario(
-            scenario!("Register")
+            scenario!(SCENARIO)
                 .register_transaction(transaction!(register))
                 .set_wait_time(Duration::ZERO, Duration::ZERO)?,
         )
@@ -119,5 +385,85 @@ async fn main() -> Result<(), GooseError> {
         .execute()
         .await?;
 
+    report::write(&configuration)?;
+
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use matrix_sdk::ruma::api::client::uiaa::UiaaInfo;
+    use serde_json::{json, Value as JsonValue};
+
+    use super::next_auth_stage;
+
+    fn uiaa_info(flows: &[&[&str]], completed: &[&str]) -> UiaaInfo {
+        let flows: Vec<JsonValue> = flows
+            .iter()
+            .map(|stages| json!({ "stages": stages }))
+            .collect();
+        serde_json::from_value(json!({
+            "flows": flows,
+            "completed": completed,
+            "params": {},
+            "session": "session",
+        }))
+        .unwrap()
+    }
+
+    fn next_stage(info: &UiaaInfo, registration_token: Option<&str>) -> JsonValue {
+        serde_json::to_value(next_auth_stage(info, registration_token).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn dummy_flow() {
+        let info = uiaa_info(&[&["m.login.dummy"]], &[]);
+
+        let auth = next_stage(&info, None);
+        assert_eq!(auth["type"], "m.login.dummy");
+        assert_eq!(auth["session"], "session");
+    }
+
+    #[test]
+    fn terms_then_dummy() {
+        let flows: &[&[&str]] = &[&["m.login.terms", "m.login.dummy"]];
+
+        let auth = next_stage(&uiaa_info(flows, &[]), None);
+        assert_eq!(auth["type"], "m.login.terms");
+
+        let auth = next_stage(&uiaa_info(flows, &["m.login.terms"]), None);
+        assert_eq!(auth["type"], "m.login.dummy");
+    }
+
+    #[test]
+    fn registration_token_flow() {
+        let flows: &[&[&str]] = &[&["m.login.registration_token"], &["m.login.dummy"]];
+
+        // Without a token, the flow needing one is skipped
+        let auth = next_stage(&uiaa_info(flows, &[]), None);
+        assert_eq!(auth["type"], "m.login.dummy");
+
+        let auth = next_stage(&uiaa_info(flows, &[]), Some("token"));
+        assert_eq!(auth["type"], "m.login.registration_token");
+        assert_eq!(auth["token"], "token");
+        assert_eq!(auth["session"], "session");
+
+        let flows: &[&[&str]] = &[&["org.matrix.msc3231.login.registration_token"]];
+        let auth = next_stage(&uiaa_info(flows, &[]), Some("token"));
+        assert_eq!(auth["type"], "org.matrix.msc3231.login.registration_token");
+        assert_eq!(auth["token"], "token");
+    }
+
+    #[test]
+    fn unsupported_flows() {
+        let info = uiaa_info(
+            &[&["m.login.recaptcha"], &["m.login.registration_token"]],
+            &[],
+        );
+        let err = next_auth_stage(&info, None).unwrap_err();
+        assert!(err.starts_with("no supported registration flow"));
+
+        let info = uiaa_info(&[&["m.login.dummy"]], &["m.login.dummy"]);
+        assert!(next_auth_stage(&info, None).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Resumable progress of the provisioning binaries.
+//!
+//! Every finished item (a registered user, a created room, ...) is appended to
+//! a checkpoint file right away, so that a run that crashed halfway through
+//! can be started again and skip what was already done.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// What happened to a single provisioning item.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    /// The item was provisioned by this run.
+    Completed,
+    /// The homeserver already had the item, e.g. `M_USER_IN_USE`.
+    AlreadyDone,
+    /// The checkpoint says a previous run provisioned the item.
+    Resumed,
+    /// The item could not be provisioned, it will be retried on the next run.
+    Failed,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Outcome::Completed => "completed",
+            Outcome::AlreadyDone => "already done",
+            Outcome::Resumed => "resumed from checkpoint",
+            Outcome::Failed => "failed",
+        })
+    }
+}
+
+/// Items finished by this and previous runs, keyed by name with an optional
+/// value such as the ID of a created room.
+#[derive(Debug)]
+pub struct Checkpoint {
+    path: PathBuf,
+    done: Mutex<HashMap<String, String>>,
+    file: Mutex<File>,
+    outcomes: Mutex<BTreeMap<Outcome, usize>>,
+}
+
+impl Checkpoint {
+    /// Opens the checkpoint file, creating it if it doesn't exist yet.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let mut done = HashMap::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let line = line?;
+                let (key, value) = line.split_once('\t').unwrap_or((&line, ""));
+                done.insert(key.to_owned(), value.to_owned());
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Checkpoint {
+            path: path.to_owned(),
+            done: Mutex::new(done),
+            file: Mutex::new(file),
+            outcomes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Returns the path of the checkpoint file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the number of items finished so far, by any run.
+    pub fn len(&self) -> usize {
+        self.done.lock().unwrap().len()
+    }
+
+    /// Returns whether no item was finished yet.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the value saved with a finished item.
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.done.lock().unwrap().get(key).cloned()
+    }
+
+    /// Returns whether the item was finished.
+    pub fn is_done(&self, key: &str) -> bool {
+        self.done.lock().unwrap().contains_key(key)
+    }
+
+    /// Marks the item as finished with the given outcome and saves it, along
+    /// with an optional value, to the checkpoint file.
+    pub fn complete(&self, key: &str, value: &str, outcome: Outcome) {
+        {
+            let mut file = self.file.lock().unwrap();
+            if let Err(err) = writeln!(file, "{}\t{}", key, value).and_then(|_| file.flush()) {
+                println!(
+                    "Error writing {} to checkpoint {}: {}",
+                    key,
+                    self.path.display(),
+                    err
+                );
+            }
+        }
+
+        self.done
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), value.to_owned());
+        self.record(outcome);
+    }
+
+    /// Counts an outcome without changing the checkpoint, e.g. for skipped or
+    /// failed items.
+    pub fn record(&self, outcome: Outcome) {
+        *self.outcomes.lock().unwrap().entry(outcome).or_default() += 1;
+    }
+
+    /// Returns how many items ended with each outcome in this run.
+    pub fn summary(&self) -> String {
+        let outcomes = self.outcomes.lock().unwrap();
+        let mut lines: Vec<String> = outcomes
+            .iter()
+            .map(|(outcome, count)| format!("{}: {}", outcome, count))
+            .collect();
+        lines.push(format!(
+            "{} items done in total, see {}",
+            self.len(),
+            self.path.display()
+        ));
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Checkpoint, Outcome};
+
+    #[test]
+    fn reopen_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("create_room.checkpoint");
+
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert!(checkpoint.is_empty());
+        checkpoint.complete("Room 1", "!one:localhost", Outcome::Completed);
+        checkpoint.complete("Room 2", "", Outcome::AlreadyDone);
+        checkpoint.record(Outcome::Failed);
+        drop(checkpoint);
+
+        // A second run skips what the first one finished and adds to it
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert_eq!(checkpoint.len(), 2);
+        assert!(checkpoint.is_done("Room 1"));
+        assert!(checkpoint.is_done("Room 2"));
+        assert!(!checkpoint.is_done("Room 3"));
+        assert_eq!(checkpoint.get("Room 1").as_deref(), Some("!one:localhost"));
+        assert_eq!(checkpoint.get("Room 2").as_deref(), Some(""));
+        checkpoint.record(Outcome::Resumed);
+        checkpoint.complete("Room 3", "!three:localhost", Outcome::Completed);
+        assert!(checkpoint
+            .summary()
+            .starts_with("completed: 1\nresumed from checkpoint: 1\n3 items done in total"));
+        drop(checkpoint);
+
+        let checkpoint = Checkpoint::open(&path).unwrap();
+        assert_eq!(checkpoint.len(), 3);
+        assert_eq!(
+            checkpoint.get("Room 3").as_deref(),
+            Some("!three:localhost")
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use std::{collections::HashMap, str::FromStr};
+
+use goose::config::GooseConfiguration;
+use gumdrop::Options;
+
+/// An additional command line option understood by a binary on top of the
+/// regular Goose run-time options.
+#[derive(Debug, Clone, Copy)]
+pub struct CliOption {
+    /// Long option name, without the leading `--`.
+    pub name: &'static str,
+    /// Placeholder shown for the option value in the help output. Options
+    /// with an empty placeholder are flags that don't take a value.
+    pub meta: &'static str,
+    /// Help text shown in the help output.
+    pub help: &'static str,
+}
+
+/// Values of the additional command line options given to a binary.
+#[derive(Debug, Clone, Default)]
+pub struct CliArgs {
+    values: HashMap<&'static str, String>,
+}
+
+impl CliArgs {
+    /// Splits the process arguments into the given additional options and the
+    /// Goose configuration.
+    ///
+    /// Goose rejects any option it doesn't know about, so our own options are
+    /// removed from the argument list before the rest is handed to Goose.
+    pub fn parse(options: &[CliOption]) -> (GooseConfiguration, CliArgs) {
+        let mut args = std::env::args();
+        let program = args.next().unwrap_or_default();
+        let mut goose_args = Vec::new();
+        let mut values = HashMap::new();
+
+        while let Some(arg) = args.next() {
+            let option = options.iter().find(|option| {
+                arg == format!("--{}", option.name)
+                    || arg.starts_with(&format!("--{}=", option.name))
+            });
+
+            match option {
+                Some(option) if option.meta.is_empty() => {
+                    values.insert(option.name, String::new());
+                }
+                Some(option) => {
+                    let value = match arg.split_once('=') {
+                        Some((_, value)) => value.to_owned(),
+                        None => match args.next() {
+                            Some(value) => value,
+                            None => {
+                                eprintln!(
+                                    "{}: missing argument to option `--{}`",
+                                    program, option.name
+                                );
+                                std::process::exit(2);
+                            }
+                        },
+                    };
+                    values.insert(option.name, value);
+                }
+                None => goose_args.push(arg),
+            }
+        }
+
+        let configuration = match GooseConfiguration::parse_args_default(&goose_args) {
+            Ok(configuration) => configuration,
+            Err(err) => {
+                eprintln!("{}: {}", program, err);
+                std::process::exit(2);
+            }
+        };
+
+        if configuration.help_requested() {
+            println!("Usage: {} [OPTIONS]", program);
+            println!();
+            println!("{}", GooseConfiguration::usage());
+
+            if !options.is_empty() {
+                println!();
+                println!("Matrix:");
+
+                for option in options {
+                    let usage = format!("--{} {}", option.name, option.meta);
+                    println!("  {:<30} {}", usage, option.help);
+                }
+            }
+
+            std::process::exit(0);
+        }
+
+        (configuration, CliArgs { values })
+    }
+
+    /// Returns whether the given option or flag was set.
+    pub fn is_set(&self, name: &str) -> bool {
+        self.values.contains_key(name)
+    }
+
+    /// Returns the raw value of the given option, if it was set.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// Returns the value of the given option parsed as `T`, if it was set.
+    ///
+    /// Panics if the value cannot be parsed, since the load test cannot run
+    /// with a configuration it doesn't understand.
+    pub fn get_parsed<T>(&self, name: &str) -> Option<T>
+    where
+        T: FromStr,
+        T::Err: std::fmt::Display,
+    {
+        self.get(name).map(|value| match value.parse::<T>() {
+            Ok(value) => value,
+            Err(err) => panic!("Invalid value '{}' for option --{}: {}", value, name, err),
+        })
+    }
+}
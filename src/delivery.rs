@@ -0,0 +1,571 @@
+//! End-to-end delivery of the chat messages.
+//!
+//! The messages sent by the chat scenario carry a [`Probe`] in their content,
+//! with the time they were sent. When another user sees the message in its
+//! sync, the difference to the current time is the delivery latency, from the
+//! sender hitting send to the receiver getting the message. Both users run in
+//! the same load generator, so they share the same clock.
+//!
+//! The [`DeliveryTracker`] also remembers the IDs of the sent events, to find
+//! the ones that never reached some of the room members, and the
+//! [`OrderingTracker`] checks that the messages of a sender reach everyone in
+//! the order they were sent.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use ruma::{
+    events::AnyMessageLikeEventContent, serde::Raw, EventId, OwnedEventId, OwnedRoomId,
+    OwnedUserId, RoomId, UInt, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::value::to_raw_value;
+
+use crate::matrix::{room::MessagesOptions, with_request_name_prefix, GooseMatrixClient};
+
+/// Field of the message content holding the probe.
+pub const PROBE_FIELD: &str = "org.matrix.goose.probe";
+
+// Messages from previous runs, e.g. seen in the initial sync, have another run ID
+static RUN_ID: Lazy<String> = Lazy::new(|| now_ms().to_string());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the current time in milliseconds since the Unix epoch.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Delivery metadata embedded in the content of a message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Probe {
+    /// Identifies the load test run that sent the message.
+    pub run: String,
+    /// Unique ID of the message within the run.
+    pub id: u64,
+    /// Time the message was sent, in milliseconds since the Unix epoch.
+    pub ts: u64,
+    /// Position of the message among those of the sender in the room, when
+    /// the ordering is checked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl Probe {
+    /// Creates the probe of a message about to be sent.
+    pub fn new() -> Self {
+        Probe {
+            run: RUN_ID.to_owned(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            ts: now_ms(),
+            seq: None,
+        }
+    }
+
+    /// Sets the sequence number of the message, see [`OrderingTracker`].
+    pub fn with_sequence(mut self, seq: u64) -> Self {
+        self.seq = Some(seq);
+        self
+    }
+
+    /// Returns whether the message was sent by the current run.
+    pub fn is_current_run(&self) -> bool {
+        self.run == *RUN_ID
+    }
+
+    /// Returns the time since the message was sent, in milliseconds.
+    pub fn latency_ms(&self) -> f64 {
+        now_ms().saturating_sub(self.ts) as f64
+    }
+
+    /// Returns the given message content with the probe added to it.
+    pub fn attach(&self, content: &impl Serialize) -> Raw<AnyMessageLikeEventContent> {
+        let mut content = serde_json::to_value(content).unwrap();
+        content[PROBE_FIELD] = serde_json::to_value(self).unwrap();
+        Raw::from_json(to_raw_value(&content).unwrap())
+    }
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Deserialize)]
+struct ProbeEvent {
+    sender: OwnedUserId,
+    content: ProbeContent,
+}
+
+#[derive(Deserialize)]
+struct ProbeContent {
+    #[serde(rename = "org.matrix.goose.probe")]
+    probe: Option<Probe>,
+}
+
+/// Returns the sender and probe of a received message, if it was sent by
+/// the current run.
+pub fn received<T>(event: &Raw<T>) -> Option<(OwnedUserId, Probe)> {
+    let event = event.deserialize_as::<ProbeEvent>().ok()?;
+    event
+        .content
+        .probe
+        .filter(Probe::is_current_run)
+        .map(|probe| (event.sender, probe))
+}
+
+// Back-fill stops after this many pages of /messages per room
+const BACKFILL_PAGES: usize = 10;
+
+// Back-fill requests aren't part of the simulated user behaviour, so they get
+// their own rows in the report
+const BACKFILL_PREFIX: &str = "[backfill]";
+
+#[derive(Debug)]
+struct SentEvent {
+    room_id: OwnedRoomId,
+    sender: OwnedUserId,
+    ts: u64,
+}
+
+// The time a user was syncing and the rooms it ended up in
+#[derive(Debug)]
+struct Receiver {
+    started: u64,
+    stopped: Option<u64>,
+    rooms: HashSet<OwnedRoomId>,
+}
+
+impl Receiver {
+    fn expects(&self, event: &SentEvent, user_id: &UserId) -> bool {
+        *event.sender != *user_id
+            && self.rooms.contains(&event.room_id)
+            && self.started <= event.ts
+            && self.stopped.map_or(true, |stopped| event.ts <= stopped)
+    }
+}
+
+/// Events sent by the load test users, and which users received them.
+///
+/// A user is expected to receive the events sent to its rooms by others
+/// while it was syncing, between [`DeliveryTracker::start`] and
+/// [`DeliveryTracker::stop`].
+#[derive(Debug, Default)]
+pub struct DeliveryTracker {
+    sent: Mutex<HashMap<OwnedEventId, SentEvent>>,
+    received: Mutex<HashMap<OwnedEventId, HashSet<OwnedUserId>>>,
+    receivers: Mutex<HashMap<OwnedUserId, Receiver>>,
+}
+
+impl DeliveryTracker {
+    /// Records an event sent by a load test user.
+    pub fn sent(&self, event_id: &EventId, room_id: &RoomId, sender: &UserId) {
+        self.sent.lock().unwrap().insert(
+            event_id.to_owned(),
+            SentEvent {
+                room_id: room_id.to_owned(),
+                sender: sender.to_owned(),
+                ts: now_ms(),
+            },
+        );
+    }
+
+    /// Records that the user received the event.
+    ///
+    /// The sync of a receiver often returns an event before the response to
+    /// its sender, so the event may not be known to be sent yet. Events that
+    /// weren't sent by the load test are left out when reporting.
+    pub fn received(&self, event_id: &EventId, user_id: &UserId) {
+        self.received
+            .lock()
+            .unwrap()
+            .entry(event_id.to_owned())
+            .or_default()
+            .insert(user_id.to_owned());
+    }
+
+    /// Marks the user as receiving the events sent from now on.
+    pub fn start(&self, user_id: &UserId) {
+        self.receivers.lock().unwrap().insert(
+            user_id.to_owned(),
+            Receiver {
+                started: now_ms(),
+                stopped: None,
+                rooms: HashSet::new(),
+            },
+        );
+    }
+
+    /// Marks the user as no longer syncing, as a member of the given rooms.
+    pub fn stop(&self, user_id: &UserId, rooms: impl IntoIterator<Item = OwnedRoomId>) {
+        if let Some(receiver) = self.receivers.lock().unwrap().get_mut(user_id) {
+            receiver.stopped = Some(now_ms());
+            receiver.rooms = rooms.into_iter().collect();
+        }
+    }
+
+    /// Returns the events of the room the user should have received but
+    /// didn't.
+    pub fn missing(&self, user_id: &UserId, room_id: &RoomId) -> HashSet<OwnedEventId> {
+        let receivers = self.receivers.lock().unwrap();
+        let receiver = match receivers.get(user_id) {
+            Some(receiver) => receiver,
+            None => return HashSet::new(),
+        };
+        let received = self.received.lock().unwrap();
+
+        self.sent
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, event)| *event.room_id == *room_id && receiver.expects(event, user_id))
+            .filter(|(event_id, _)| {
+                !received
+                    .get(*event_id)
+                    .map_or(false, |users| users.contains(user_id))
+            })
+            .map(|(event_id, _)| event_id.to_owned())
+            .collect()
+    }
+
+    /// Looks for the events the user missed in its sync in the history of its
+    /// rooms, since they might have arrived after its last sync.
+    pub async fn backfill(&self, client: &GooseMatrixClient) {
+        let user_id = match client.user_id() {
+            Some(user_id) => user_id.to_owned(),
+            None => return,
+        };
+
+        for room in client.joined_rooms() {
+            let mut missing = self.missing(&user_id, room.room_id());
+            let mut from = None;
+
+            for _ in 0..BACKFILL_PAGES {
+                if missing.is_empty() {
+                    break;
+                }
+
+                let mut options = MessagesOptions::backward();
+                options.from = from;
+                options.limit = UInt::new(100).unwrap();
+
+                let messages =
+                    match with_request_name_prefix(BACKFILL_PREFIX, room.messages(options)).await {
+                        Ok(messages) => messages,
+                        Err(_) => break,
+                    };
+
+                for event in messages.chunk {
+                    if let Ok(Some(event_id)) = event.event.get_field::<OwnedEventId>("event_id") {
+                        if missing.remove(&event_id) {
+                            self.received(&event_id, &user_id);
+                        }
+                    }
+                }
+
+                match messages.end {
+                    Some(end) => from = Some(end),
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Returns how many of the expected deliveries were missing, per room.
+    pub fn report(&self) -> LossReport {
+        let receivers = self.receivers.lock().unwrap();
+        let received = self.received.lock().unwrap();
+        let mut rooms: BTreeMap<OwnedRoomId, RoomLoss> = BTreeMap::new();
+
+        for (event_id, event) in self.sent.lock().unwrap().iter() {
+            let loss = rooms.entry(event.room_id.to_owned()).or_default();
+            loss.events += 1;
+
+            for (user_id, receiver) in receivers.iter() {
+                if !receiver.expects(event, user_id) {
+                    continue;
+                }
+
+                loss.expected += 1;
+                if !received
+                    .get(event_id)
+                    .map_or(false, |users| users.contains(user_id))
+                {
+                    loss.missing += 1;
+                }
+            }
+        }
+
+        LossReport { rooms }
+    }
+}
+
+/// Deliveries of the events sent to a room.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RoomLoss {
+    /// Number of events sent to the room.
+    pub events: usize,
+    /// Number of times a member was expected to receive one of the events.
+    pub expected: usize,
+    /// Number of those deliveries that never happened.
+    pub missing: usize,
+}
+
+/// Missing deliveries of a run, see [`DeliveryTracker::report`].
+#[derive(Debug, Clone, Default)]
+pub struct LossReport {
+    pub rooms: BTreeMap<OwnedRoomId, RoomLoss>,
+}
+
+impl LossReport {
+    /// Returns the number of expected deliveries in all rooms.
+    pub fn expected(&self) -> usize {
+        self.rooms.values().map(|room| room.expected).sum()
+    }
+
+    /// Returns the number of missing deliveries in all rooms.
+    pub fn missing(&self) -> usize {
+        self.rooms.values().map(|room| room.missing).sum()
+    }
+
+    /// Returns the share of the expected deliveries that were missing.
+    pub fn loss_ratio(&self) -> f64 {
+        match self.expected() {
+            0 => 0.0,
+            expected => self.missing() as f64 / expected as f64,
+        }
+    }
+
+    /// Returns the totals, followed by the rooms with missing deliveries.
+    pub fn summary(&self) -> String {
+        let mut lines = vec![format!(
+            "{} of {} deliveries missing ({:.2}%)",
+            self.missing(),
+            self.expected(),
+            self.loss_ratio() * 100.0
+        )];
+
+        for (room_id, room) in self.rooms.iter().filter(|(_, room)| room.missing > 0) {
+            lines.push(format!(
+                "{}: {} of {} deliveries of {} events missing",
+                room_id, room.missing, room.expected, room.events
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+// Upper bound of the ordering violations listed in the summary
+const MAX_LISTED_VIOLATIONS: usize = 50;
+
+/// How the ordering of the messages of a sender was broken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The message arrived after a later message of the same sender.
+    OutOfOrder,
+    /// The message arrived more than once.
+    Duplicate,
+}
+
+/// A message of a sender that reached a receiver out of order or twice.
+#[derive(Debug, Clone)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub receiver: OwnedUserId,
+    pub sender: OwnedUserId,
+    pub room_id: OwnedRoomId,
+    pub seq: u64,
+    /// The highest sequence number the receiver had seen before.
+    pub last_seq: u64,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ViolationKind::OutOfOrder => write!(
+                f,
+                "{} got message {} of {} in {} after message {}",
+                self.receiver, self.seq, self.sender, self.room_id, self.last_seq
+            ),
+            ViolationKind::Duplicate => write!(
+                f,
+                "{} got message {} of {} in {} twice",
+                self.receiver, self.seq, self.sender, self.room_id
+            ),
+        }
+    }
+}
+
+// Messages of a sender in a room seen by a receiver
+#[derive(Debug, Default)]
+struct SeenMessages {
+    last_seq: u64,
+    seqs: HashSet<u64>,
+}
+
+/// Sequence numbers of the messages of each sender in each room, and the
+/// order in which the receivers got them.
+#[derive(Debug, Default)]
+pub struct OrderingTracker {
+    next_seqs: Mutex<HashMap<(OwnedUserId, OwnedRoomId), u64>>,
+    seen: Mutex<HashMap<(OwnedUserId, OwnedUserId, OwnedRoomId), SeenMessages>>,
+    violations: Mutex<Vec<Violation>>,
+}
+
+impl OrderingTracker {
+    /// Returns the sequence number of the next message of the sender in the
+    /// room, starting at 1.
+    pub fn next_seq(&self, sender: &UserId, room_id: &RoomId) -> u64 {
+        let mut next_seqs = self.next_seqs.lock().unwrap();
+        let seq = next_seqs
+            .entry((sender.to_owned(), room_id.to_owned()))
+            .or_default();
+        *seq += 1;
+        *seq
+    }
+
+    /// Checks a message the receiver got against the ones it got before from
+    /// the same sender in the same room.
+    pub fn received(&self, receiver: &UserId, sender: &UserId, room_id: &RoomId, seq: u64) {
+        let mut seen = self.seen.lock().unwrap();
+        let messages = seen
+            .entry((receiver.to_owned(), sender.to_owned(), room_id.to_owned()))
+            .or_default();
+
+        let kind = if !messages.seqs.insert(seq) {
+            ViolationKind::Duplicate
+        } else if seq < messages.last_seq {
+            ViolationKind::OutOfOrder
+        } else {
+            messages.last_seq = seq;
+            return;
+        };
+
+        self.violations.lock().unwrap().push(Violation {
+            kind,
+            receiver: receiver.to_owned(),
+            sender: sender.to_owned(),
+            room_id: room_id.to_owned(),
+            seq,
+            last_seq: messages.last_seq,
+        });
+    }
+
+    /// Returns the ordering violations seen so far.
+    pub fn violations(&self) -> Vec<Violation> {
+        self.violations.lock().unwrap().clone()
+    }
+
+    /// Returns the number of violations of each kind, followed by the first
+    /// ones.
+    pub fn summary(&self) -> String {
+        let violations = self.violations.lock().unwrap();
+        let count = |kind| violations.iter().filter(|v| v.kind == kind).count();
+
+        let mut lines = vec![
+            format!("Out of order: {}", count(ViolationKind::OutOfOrder)),
+            format!("Duplicates: {}", count(ViolationKind::Duplicate)),
+        ];
+        lines.extend(
+            violations
+                .iter()
+                .take(MAX_LISTED_VIOLATIONS)
+                .map(Violation::to_string),
+        );
+        if violations.len() > MAX_LISTED_VIOLATIONS {
+            lines.push(format!(
+                "... and {} more",
+                violations.len() - MAX_LISTED_VIOLATIONS
+            ));
+        }
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ruma::{event_id, room_id, user_id};
+
+    use super::{DeliveryTracker, OrderingTracker, ViolationKind};
+
+    #[test]
+    fn received_before_sent() {
+        let tracker = DeliveryTracker::default();
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+        let room_id = room_id!("!room:localhost");
+        let event_id = event_id!("$event:localhost");
+
+        tracker.start(bob);
+        // Bob's sync returns the event before Alice gets the response to her send
+        tracker.received(event_id, bob);
+        tracker.received(event_id!("$other:localhost"), bob);
+        tracker.sent(event_id, room_id, alice);
+        tracker.stop(bob, [room_id.to_owned()]);
+
+        assert!(tracker.missing(bob, room_id).is_empty());
+        let report = tracker.report();
+        assert_eq!(report.expected(), 1);
+        assert_eq!(report.missing(), 0);
+    }
+
+    #[test]
+    fn next_seq_per_sender_and_room() {
+        let tracker = OrderingTracker::default();
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+        let room_id = room_id!("!room:localhost");
+
+        assert_eq!(tracker.next_seq(alice, room_id), 1);
+        assert_eq!(tracker.next_seq(alice, room_id), 2);
+        assert_eq!(tracker.next_seq(bob, room_id), 1);
+        assert_eq!(tracker.next_seq(alice, room_id!("!other:localhost")), 1);
+    }
+
+    #[test]
+    fn ordering_violations() {
+        let tracker = OrderingTracker::default();
+        let alice = user_id!("@alice:localhost");
+        let bob = user_id!("@bob:localhost");
+        let carol = user_id!("@carol:localhost");
+        let room_id = room_id!("!room:localhost");
+
+        for seq in [1, 2, 4] {
+            tracker.received(bob, alice, room_id, seq);
+        }
+        // Each receiver and room is checked on its own
+        tracker.received(carol, alice, room_id, 1);
+        tracker.received(bob, alice, room_id!("!other:localhost"), 1);
+        assert!(tracker.violations().is_empty());
+
+        tracker.received(bob, alice, room_id, 3);
+        tracker.received(bob, alice, room_id, 4);
+
+        let violations = tracker.violations();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].kind, ViolationKind::OutOfOrder);
+        assert_eq!((violations[0].seq, violations[0].last_seq), (3, 4));
+        assert_eq!(violations[1].kind, ViolationKind::Duplicate);
+        assert_eq!(violations[1].seq, 4);
+        assert_eq!(
+            violations[0].to_string(),
+            "@bob:localhost got message 3 of @alice:localhost in !room:localhost after message 4"
+        );
+        assert!(tracker
+            .summary()
+            .starts_with("Out of order: 1\nDuplicates: 1\n"));
+    }
+}
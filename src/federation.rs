@@ -0,0 +1,78 @@
+//! Load tests spanning several federated homeservers.
+//!
+//! Users from `users.csv` with a `host` column talk to that homeserver instead
+//! of the one given with `--host`. As soon as any user has its own homeserver,
+//! the requests of every user are reported per homeserver, e.g.
+//! `[hs1.example.org:8448] /_matrix/client/v3/sync`, to compare how each
+//! server copes with the federated traffic.
+
+use std::collections::BTreeMap;
+
+use goose::prelude::*;
+
+use crate::{
+    fixtures::User,
+    matrix::{GooseMatrixClient, HttpError},
+};
+
+/// Returns whether any of the users has a homeserver of its own.
+pub fn is_federated(users: &[User]) -> bool {
+    users.iter().any(|user| user.host.is_some())
+}
+
+/// Points the Goose user at the homeserver of the fixture user, if it has
+/// one of its own.
+pub fn set_homeserver(user: &mut GooseUser, csv_user: &User) -> Result<(), Box<TransactionError>> {
+    match &csv_user.host {
+        Some(host) => user.set_base_url(host),
+        None => Ok(()),
+    }
+}
+
+/// Returns the label prefixed to the request names of the Goose user, when
+/// the users span several homeservers.
+pub fn homeserver_label(user: &GooseUser, users: &[User]) -> Option<String> {
+    if !is_federated(users) {
+        return None;
+    }
+
+    let url = &user.base_url;
+    Some(match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("[{}:{}]", host, port),
+        (Some(host), None) => format!("[{}]", host),
+        _ => format!("[{}]", url),
+    })
+}
+
+/// Creates a client for the homeserver of the Goose user, labelling its
+/// requests with [`homeserver_label`].
+pub async fn client(user: &GooseUser, users: &[User]) -> Result<GooseMatrixClient, HttpError> {
+    let mut builder = GooseMatrixClient::builder(user.weighted_users_index)
+        .homeserver_url(user.base_url.to_owned());
+
+    if let Some(label) = homeserver_label(user, users) {
+        builder = builder.request_name_prefix(label);
+    }
+
+    builder
+        .build()
+        .await
+        .map_err(|err| err.assert_valid_builder_args())
+}
+
+/// Returns how many of the users are on each homeserver, counting the users
+/// without a homeserver of their own towards `default_host`.
+pub fn summary(users: &[User], default_host: &str) -> String {
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for user in users {
+        *counts
+            .entry(user.host.as_deref().unwrap_or(default_host))
+            .or_default() += 1;
+    }
+
+    counts
+        .iter()
+        .map(|(host, count)| format!("{}: {} users", host, count))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
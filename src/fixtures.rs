@@ -0,0 +1,180 @@
+//! Formats of the `users.csv`, `rooms.json` and `rooms_created.json` fixtures
+//! shared by the generator and the load test binaries.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::Path,
+};
+
+use ruma::{
+    api::client::room::create_room::v3::RoomPreset,
+    events::room::history_visibility::HistoryVisibility, IdParseError, OwnedRoomAliasId,
+    OwnedRoomId, OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+/// A row of `users.csv`.
+///
+/// The username is either a localpart on the homeserver under test or a full
+/// Matrix ID, for homeservers whose server name differs from their host.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    pub password: String,
+    /// Optional column overriding the workload persona picked by ratio.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// Optional column with the registration token of the user, overriding
+    /// the one given to `register` on the command line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registration_token: Option<String>,
+    /// Optional column with the URL of the homeserver of the user, for load
+    /// tests spanning several federated homeservers. Users without one use
+    /// the `--host` of the run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+}
+
+impl User {
+    /// Returns the localpart of the username.
+    pub fn localpart(&self) -> &str {
+        localpart(&self.username)
+    }
+}
+
+/// Returns the localpart of a username from the fixtures, which may be a
+/// full Matrix ID.
+pub fn localpart(name: &str) -> &str {
+    name.strip_prefix('@')
+        .and_then(|user_id| user_id.split_once(':'))
+        .map_or(name, |(localpart, _)| localpart)
+}
+
+/// Returns the Matrix ID of a username from the fixtures, completing bare
+/// localparts with the given server name.
+pub fn user_id(name: &str, server_name: &ServerName) -> Result<OwnedUserId, IdParseError> {
+    UserId::parse_with_server_name(name, server_name)
+}
+
+/// The kind of room to create for a [`RoomInfo`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomKind {
+    /// A 1:1 direct chat between the creator and its single user.
+    Dm,
+    /// An invite-only room.
+    #[default]
+    Private,
+    /// A room published in the room directory, which the users join from
+    /// there instead of being invited.
+    Public,
+    /// A space containing the `children` rooms of the same creator.
+    Space,
+}
+
+impl RoomKind {
+    fn is_private(&self) -> bool {
+        *self == RoomKind::Private
+    }
+}
+
+/// A room of `rooms.json`, created by `creator` for `users` to join.
+///
+/// The optional fields are passed on to the room creation request, leaving
+/// the homeserver defaults in place when unset.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub creator: String,
+    pub name: String,
+    pub users: Vec<String>,
+    /// Rooms without a type are private rooms.
+    #[serde(rename = "type", default, skip_serializing_if = "RoomKind::is_private")]
+    pub kind: RoomKind,
+    /// Names of the rooms of a space, which must have the same creator.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room_version: Option<RoomVersionId>,
+    /// One of `private_chat`, `public_chat` or `trusted_private_chat`,
+    /// overriding the preset implied by the room type.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<RoomPreset>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Local part of the alias to publish the room under.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// Enables end-to-end encryption with `m.megolm.v1.aes-sha2`.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub encrypted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_visibility: Option<HistoryVisibility>,
+    /// Additional state events, e.g. `m.room.join_rules`, as objects with a
+    /// `type`, a `content` and an optional `state_key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub initial_state: Vec<JsonValue>,
+    /// Content merged into the default `m.room.power_levels` event.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub power_levels: Option<JsonValue>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+/// The contents of `rooms.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RoomList {
+    pub creators: Vec<RoomInfo>,
+}
+
+/// Default path of the file mapping fixture rooms to the rooms actually
+/// created on the homeserver.
+pub const CREATED_ROOMS_FILE: &str = "rooms_created.json";
+
+/// A room of `rooms.json` as created by the `create_room` binary.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedRoom {
+    pub room_id: OwnedRoomId,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<OwnedRoomAliasId>,
+    /// Number of users expected in the room, including its creator.
+    pub members: usize,
+}
+
+/// The contents of `rooms_created.json`, keyed by fixture room name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct CreatedRooms {
+    pub rooms: BTreeMap<String, CreatedRoom>,
+}
+
+impl CreatedRooms {
+    /// Reads the created rooms from the given file.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        Ok(serde_json::from_reader(BufReader::new(file))?)
+    }
+
+    /// Writes the created rooms to the given file, replacing it.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let file = File::create(path)?;
+        Ok(serde_json::to_writer_pretty(BufWriter::new(file), self)?)
+    }
+
+    /// Returns the ID of the fixture room with the given name.
+    pub fn room_id(&self, name: &str) -> Option<&RoomId> {
+        self.rooms.get(name).map(|room| room.room_id.as_ref())
+    }
+
+    /// Returns the fixture room with the most members.
+    pub fn biggest(&self) -> Option<(&str, &CreatedRoom)> {
+        self.rooms
+            .iter()
+            .max_by_key(|(_, room)| room.members)
+            .map(|(name, room)| (name.as_str(), room))
+    }
+}
@@ -1,5 +1,17 @@
 
+pub mod admin;
+pub mod checkpoint;
+pub mod cli;
+pub mod delivery;
+pub mod federation;
+pub mod fixtures;
 pub mod matrix;
+pub mod metrics;
+pub mod report;
+pub mod rng;
+pub mod scheduler;
+pub mod session;
+pub mod workload;
 
 use std::sync::Arc;
 use lazy_static::lazy_static;
@@ -89,6 +89,7 @@ pub struct GooseClientBuilder {
     appservice_mode: bool,
     server_versions: Option<Box<[MatrixVersion]>>,
     handle_refresh_tokens: bool,
+    request_name_prefix: Option<String>,
     goose_user_index: usize,
 }
 
@@ -105,6 +106,7 @@ impl GooseClientBuilder {
             appservice_mode: false,
             server_versions: None,
             handle_refresh_tokens: false,
+            request_name_prefix: None,
             goose_user_index,
         }
     }
@@ -194,6 +196,15 @@ impl GooseClientBuilder {
         self
     }
 
+    /// Set a prefix for the names of all requests reported to Goose.
+    ///
+    /// This allows grouping the request metrics of a subset of users, e.g.
+    /// all users with the same persona, into their own rows of the report.
+    pub fn request_name_prefix(mut self, prefix: impl AsRef<str>) -> Self {
+        self.request_name_prefix = Some(prefix.as_ref().to_owned());
+        self
+    }
+
     /// Set the default timeout, fail and retry behavior for all HTTP requests.
     pub fn request_config(mut self, request_config: RequestConfig) -> Self {
         self.request_config = request_config;
@@ -366,7 +377,11 @@ impl GooseClientBuilder {
         };
 
         let base_client = BaseClient::with_store_config(store_config);
-        let http_client = HttpClient::new(inner_http_client.clone(), self.request_config);
+        let http_client = HttpClient::new(
+            inner_http_client.clone(),
+            self.request_config,
+            self.request_name_prefix,
+        );
 
         let mut authentication_issuer = None;
         #[cfg(feature = "experimental-sliding-sync")]
@@ -16,6 +16,7 @@
 
 use std::io::Error as IoError;
 
+use goose::{goose::TransactionError, metrics::GooseRequestMetric};
 #[cfg(feature = "qrcode")]
 use matrix_sdk_base::crypto::ScanError;
 #[cfg(feature = "e2e-encryption")]
@@ -101,6 +102,34 @@ pub enum HttpError {
     #[error(transparent)]
     IntoHttp(#[from] IntoHttpError),
 
+    /// The URL of the request could not be parsed by Goose.
+    #[error("invalid request URL: {0}")]
+    Url(UrlParseError),
+
+    /// Goose recorded the request as failed.
+    #[error("request {} failed: {}", .0.name, .0.error)]
+    RequestFailed(Box<GooseRequestMetric>),
+
+    /// Goose canceled the request, because the throttled load test ended.
+    #[error("request canceled because the throttled load test ended")]
+    RequestCanceled(#[source] Box<TransactionError>),
+
+    /// Goose could not send the metrics of the request to its parent thread.
+    #[error("failed to send the request metrics to Goose")]
+    MetricsFailed(#[source] Box<TransactionError>),
+
+    /// Goose could not send the request log to its logger thread.
+    #[error("failed to send the request log to the Goose logger")]
+    LoggerFailed(#[source] Box<TransactionError>),
+
+    /// Goose does not support the method of the request.
+    #[error("unsupported HTTP request method: {0}")]
+    InvalidMethod(String),
+
+    /// No Goose user was registered to send the requests of the client.
+    #[error("no Goose user is registered for index {0}")]
+    UnregisteredGooseUser(usize),
+
     /// The given request can't be cloned and thus can't be retried.
     #[error("The request cannot be cloned")]
     UnableToCloneRequest,
@@ -359,6 +388,22 @@ impl From<FromHttpResponseError<ruma::api::error::MatrixError>> for HttpError {
     }
 }
 
+impl From<Box<TransactionError>> for HttpError {
+    fn from(err: Box<TransactionError>) -> Self {
+        match *err {
+            TransactionError::Reqwest(e) => Self::Reqwest(e),
+            TransactionError::Url(e) => Self::Url(e),
+            TransactionError::RequestFailed { raw_request } => {
+                Self::RequestFailed(Box::new(raw_request))
+            }
+            e @ TransactionError::RequestCanceled { .. } => Self::RequestCanceled(Box::new(e)),
+            e @ TransactionError::MetricsFailed { .. } => Self::MetricsFailed(Box::new(e)),
+            e @ TransactionError::LoggerFailed { .. } => Self::LoggerFailed(Box::new(e)),
+            TransactionError::InvalidMethod { method } => Self::InvalidMethod(method.to_string()),
+        }
+    }
+}
+
 impl From<SdkBaseError> for Error {
     fn from(e: SdkBaseError) -> Self {
         match e {
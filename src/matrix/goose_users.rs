@@ -0,0 +1,99 @@
+//! Registry of the Goose users on whose behalf the clients send their
+//! requests.
+//!
+//! Goose only lends a [`GooseUser`] to the transaction it is running, while a
+//! client also sends requests from other tasks, such as its sync loop. The
+//! requests therefore go through a copy of the user made when it is
+//! registered, sharing the channels through which Goose collects the metrics,
+//! logs and throttling of the original.
+//!
+//! A request waits for the copy to be free, so that all the requests of a user
+//! share the request cadence Goose uses to mitigate coordinated omission. A
+//! long-polling sync thus holds up the other requests of its user until it
+//! returns.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use goose::prelude::*;
+use once_cell::sync::Lazy;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// The Goose users registered with [`GooseUserRegistry::register`].
+pub static GOOSE_USERS: Lazy<GooseUserRegistry> = Lazy::new(GooseUserRegistry::default);
+
+/// Goose users by their index, as passed to
+/// [`GooseMatrixClient::builder`](super::GooseMatrixClient::builder).
+#[derive(Default)]
+pub struct GooseUserRegistry {
+    users: RwLock<HashMap<usize, RegisteredUser>>,
+}
+
+// Sends the requests of the user, keeping track of their cadence
+type RegisteredUser = Arc<Mutex<GooseUser>>;
+
+impl GooseUserRegistry {
+    /// Registers the given Goose user, running the scenario with the given
+    /// index and name, so that the clients created with its index send their
+    /// requests on its behalf.
+    ///
+    /// Goose doesn't tell which scenario a user runs, so binaries with a
+    /// single scenario pass `0` and its name.
+    ///
+    /// Registering a user again replaces the previous registration, e.g. after
+    /// its base URL changed.
+    pub fn register(
+        &self,
+        user: &GooseUser,
+        scenario_index: usize,
+        scenario_name: &str,
+    ) -> Result<(), GooseError> {
+        let shared = copy_user(user, scenario_index, scenario_name)?;
+
+        self.users
+            .write()
+            .unwrap()
+            .insert(user.weighted_users_index, Arc::new(Mutex::new(shared)));
+        Ok(())
+    }
+
+    /// Returns whether a Goose user was registered for the given index.
+    pub fn is_registered(&self, index: usize) -> bool {
+        self.users.read().unwrap().contains_key(&index)
+    }
+
+    /// Lends the registered Goose user to send one request with, waiting
+    /// for the request it is lent to, if any, to return it.
+    pub(crate) async fn user(&self, index: usize) -> Option<OwnedMutexGuard<GooseUser>> {
+        let registered = Arc::clone(self.users.read().unwrap().get(&index)?);
+
+        Some(registered.lock_owned().await)
+    }
+}
+
+// Session data and the per-transaction state are left out, they only matter
+// to the transactions Goose runs for the original user
+fn copy_user(
+    user: &GooseUser,
+    scenario_index: usize,
+    scenario_name: &str,
+) -> Result<GooseUser, GooseError> {
+    let mut copy = GooseUser::new(
+        scenario_index,
+        scenario_name.to_owned(),
+        user.base_url.clone(),
+        &user.config,
+        user.load_test_hash,
+        Some(user.client.clone()),
+    )?;
+    copy.started = user.started;
+    copy.logger = user.logger.clone();
+    copy.throttle = user.throttle.clone();
+    copy.is_throttled = user.is_throttled;
+    copy.metrics_channel = user.metrics_channel.clone();
+    copy.weighted_users_index = user.weighted_users_index;
+
+    Ok(copy)
+}
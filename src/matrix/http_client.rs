@@ -15,9 +15,10 @@
 use std::{
     any::type_name,
     fmt::Debug,
+    future::Future,
     sync::{
         atomic::{AtomicU64, Ordering},
-        Arc,
+        Arc, Mutex,
     },
     time::Duration,
 };
@@ -29,21 +30,60 @@ use matrix_sdk_common::AsyncTraitDeps;
 use ruma::{
     api::{
         error::{FromHttpResponseError, IntoHttpError},
-        AuthScheme, IncomingResponse, MatrixVersion, OutgoingRequest, OutgoingRequestAppserviceExt,
-        SendAccessToken,
+        AuthScheme, IncomingResponse, MatrixVersion, Metadata, OutgoingRequest,
+        OutgoingRequestAppserviceExt, SendAccessToken,
     },
     UserId,
 };
 use tracing::{debug, field::debug, instrument, trace};
 
 // use crate::{config::RequestConfig, error::HttpError};
-use goose::{prelude::*};
+use goose::{metrics::GooseRequestMetric, prelude::*};
 use reqwest::RequestBuilder;
 use crate::matrix::{
     config::RequestConfig,
     error::HttpError,
     GOOSE_USERS,
 };
+use crate::metrics::{PAYLOAD_SIZES, RATE_LIMITS};
+
+tokio::task_local! {
+    static SCOPED_NAME_PREFIX: String;
+    static EXPECTED_ERRCODES: &'static [&'static str];
+}
+
+/// Runs the given future with a prefix added to the names of the requests it
+/// sends, after the prefix of the client if it has one.
+///
+/// This keeps requests made for the load test itself, e.g. to check the
+/// results at the end of a run, apart from the ones of the simulated users in
+/// the Goose report.
+pub async fn with_request_name_prefix<F: Future>(
+    prefix: impl Into<String>,
+    future: F,
+) -> F::Output {
+    SCOPED_NAME_PREFIX.scope(prefix.into(), future).await
+}
+
+/// Runs the given future with the given Matrix `errcode`s counted as successes
+/// in the Goose report, for the requests it sends.
+///
+/// This is for errors the caller handles as an outcome of its own, e.g.
+/// `M_USER_IN_USE` when registering users that may already exist.
+pub async fn with_expected_errcodes<F: Future>(
+    errcodes: &'static [&'static str],
+    future: F,
+) -> F::Output {
+    EXPECTED_ERRCODES.scope(errcodes, future).await
+}
+
+/// Returns whether the given `errcode` was passed to the surrounding
+/// [`with_expected_errcodes`], for the requests sent through Goose directly.
+pub(crate) fn is_expected_errcode(errcode: &str) -> bool {
+    EXPECTED_ERRCODES
+        .try_with(|errcodes| errcodes.contains(&errcode))
+        .unwrap_or(false)
+}
 
 // pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 pub(crate) const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
@@ -65,6 +105,10 @@ pub trait HttpSend: AsyncTraitDeps {
     ///   `Request`.
     ///
     /// * `timeout` - A timeout for the full request > response cycle.
+    ///
+    /// * `goose_user_index` - Index of the Goose user sending the request.
+    ///
+    /// * `request_name` - Name of the request shown in the Goose report.
     /// # Examples
     ///
     /// ```
@@ -107,6 +151,7 @@ pub trait HttpSend: AsyncTraitDeps {
         request: http::Request<Bytes>,
         timeout: Duration,
         goose_user_index: usize,
+        request_name: &str,
     ) -> Result<http::Response<Bytes>, HttpError>;
 }
 
@@ -114,12 +159,22 @@ pub trait HttpSend: AsyncTraitDeps {
 pub(crate) struct HttpClient {
     pub(crate) inner: Arc<dyn HttpSend>,
     pub(crate) request_config: RequestConfig,
+    pub(crate) request_name_prefix: Option<String>,
     next_request_id: Arc<AtomicU64>,
 }
 
 impl HttpClient {
-    pub(crate) fn new(inner: Arc<dyn HttpSend>, request_config: RequestConfig) -> Self {
-        HttpClient { inner, request_config, next_request_id: AtomicU64::new(0).into() }
+    pub(crate) fn new(
+        inner: Arc<dyn HttpSend>,
+        request_config: RequestConfig,
+        request_name_prefix: Option<String>,
+    ) -> Self {
+        HttpClient {
+            inner,
+            request_config,
+            request_name_prefix,
+            next_request_id: AtomicU64::new(0).into(),
+        }
     }
 
     fn get_request_id(&self) -> String {
@@ -180,6 +235,7 @@ impl HttpClient {
         request: http::Request<Bytes>,
         config: RequestConfig,
         goose_user_index: usize,
+        request_name: &str,
     ) -> Result<(http::StatusCode, ByteSize, R::IncomingResponse), HttpError>
     where
         R: OutgoingRequest + Debug,
@@ -187,6 +243,8 @@ impl HttpClient {
     {
         #[cfg(not(target_arch = "wasm32"))]
         let ret = {
+            use std::time::Instant;
+
             use backoff::{future::retry, Error as RetryError, ExponentialBackoff};
             use ruma::api::client::error::{
                 ErrorBody as ClientApiErrorBody, ErrorKind as ClientApiErrorKind,
@@ -198,6 +256,11 @@ impl HttpClient {
             let backoff =
                 ExponentialBackoff { max_elapsed_time: config.retry_timeout, ..Default::default() };
             let retry_count = AtomicU64::new(1);
+            let attempts = AtomicU64::new(0);
+            // The Goose metric and Matrix errcode of the last attempt, if it failed
+            let last_failure: Mutex<Option<(GooseRequestMetric, String)>> = Mutex::new(None);
+            // When the response of the last attempt came in, if it was rate limited
+            let rate_limited_at: Mutex<Option<Instant>> = Mutex::new(None);
 
             let send_request = || async {
                 let stop = if let Some(retry_limit) = config.retry_limit {
@@ -240,14 +303,45 @@ impl HttpClient {
                     }
                 };
 
+                // Retries are reported apart from the first attempts
+                let attempt_name = if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                    request_name.to_owned()
+                } else {
+                    format!("{request_name} (retry)")
+                };
+                *last_failure.lock().unwrap() = None;
+
+                // The backoff decides how long a rate limited request actually waits,
+                // and whether it is retried at all, so the wait is measured here
+                if let Some(rate_limited_at) = rate_limited_at.lock().unwrap().take() {
+                    RATE_LIMITS.record_wait(request_name, rate_limited_at.elapsed());
+                }
+
                 let response = self
                     .inner
                     // .send_request(clone_request(&request), config.timeout)
-                    .send_request(clone_request(&request), config.timeout, goose_user_index)
+                    .send_request(
+                        clone_request(&request),
+                        config.timeout,
+                        goose_user_index,
+                        &attempt_name,
+                    )
                     .await
                     .map_err(error_type)?;
 
                 let status_code = response.status();
+                if !status_code.is_success() {
+                    let errcode = matrix_errcode(&response);
+                    if errcode.as_deref() == Some("M_LIMIT_EXCEEDED") {
+                        RATE_LIMITS.record(request_name, retry_after(&response));
+                        *rate_limited_at.lock().unwrap() = Some(Instant::now());
+                    }
+
+                    let metric = response.extensions().get::<GooseRequestMetric>().cloned();
+                    if let (Some(metric), Some(errcode)) = (metric, errcode) {
+                        *last_failure.lock().unwrap() = Some((metric, errcode));
+                    }
+                }
                 let response_size = ByteSize(response.body().len().try_into().unwrap_or(u64::MAX));
 
                 let response = R::IncomingResponse::try_from_http_response(response)
@@ -256,7 +350,18 @@ impl HttpClient {
                 Ok((status_code, response_size, response))
             };
 
-            retry::<_, HttpError, _, _, _>(backoff, send_request).await?
+            let result = retry::<_, HttpError, _, _, _>(backoff, send_request).await;
+            if result.is_err() {
+                if let Some((metric, errcode)) = last_failure.into_inner().unwrap() {
+                    if is_expected_errcode(&errcode) {
+                        record_matrix_success(goose_user_index, metric).await;
+                    } else {
+                        record_matrix_failure(goose_user_index, metric, &errcode).await;
+                    }
+                }
+            }
+
+            result?
         };
 
         #[cfg(target_arch = "wasm32")]
@@ -349,18 +454,28 @@ impl HttpClient {
         #[cfg(not(feature = "experimental-sliding-sync"))]
         span.record("path", request.uri().path());
 
+        let mut request_name = endpoint_name(&R::METADATA, &request);
+        if let Ok(prefix) = SCOPED_NAME_PREFIX.try_with(Clone::clone) {
+            request_name = format!("{prefix} {request_name}");
+        }
+        if let Some(prefix) = &self.request_name_prefix {
+            request_name = format!("{prefix} {request_name}");
+        }
+
         debug!("Sending request");
         // match self.send_request::<R>(request, config).await {
-        match self.send_request::<R>(request, config, goose_user_index).await {
+        match self.send_request::<R>(request, config, goose_user_index, &request_name).await {
             Ok((status_code, response_size, response)) => {
                 span.record("status", status_code.as_u16())
                     .record("response_size", response_size.to_string_as(true));
                 debug!("Got response");
+                PAYLOAD_SIZES.record(&request_name, request_size, Some(response_size));
 
                 Ok(response)
             }
             Err(e) => {
                 debug!("Error while sending request: {e:?}");
+                PAYLOAD_SIZES.record(&request_name, request_size, None);
 
                 Err(e)
             }
@@ -368,6 +483,78 @@ impl HttpClient {
     }
 }
 
+// Returns the `errcode` of a Matrix error response
+#[cfg(not(target_arch = "wasm32"))]
+fn matrix_errcode(response: &http::Response<Bytes>) -> Option<String> {
+    let body: serde_json::Value = serde_json::from_slice(response.body()).ok()?;
+    body.get("errcode")?.as_str().map(ToOwned::to_owned)
+}
+
+// Returns the delay a `M_LIMIT_EXCEEDED` response asks to wait before retrying
+#[cfg(not(target_arch = "wasm32"))]
+fn retry_after(response: &http::Response<Bytes>) -> Option<Duration> {
+    let body: serde_json::Value = serde_json::from_slice(response.body()).ok()?;
+    body.get("retry_after_ms")?.as_u64().map(Duration::from_millis)
+}
+
+// Adds the errcode of a request that failed for good to the Goose errors table,
+// next to the status code Goose recorded. Goose only sends an update of a request
+// metric when its success changes, so the request is first flipped back to a
+// success without an error.
+//
+// Goose thus receives two updates of the metric: the first one moves the request
+// from the failures to the successes, the second one moves it back and records
+// the errcode. The counts of the report are unchanged, but both updates show up
+// in the request log, if there is one.
+#[cfg(not(target_arch = "wasm32"))]
+async fn record_matrix_failure(
+    goose_user_index: usize,
+    mut metric: GooseRequestMetric,
+    errcode: &str,
+) {
+    if let Some(user) = GOOSE_USERS.user(goose_user_index).await {
+        metric.error.clear();
+        let _ = user.set_success(&mut metric);
+        let _ = user.set_failure(errcode, &mut metric, None, None);
+    }
+}
+
+// Counts a request that failed with an errcode passed to `with_expected_errcodes`
+// as a success, with a single update of the metric Goose recorded as a failure.
+// The error is cleared so that the update doesn't add to the errors table.
+#[cfg(not(target_arch = "wasm32"))]
+async fn record_matrix_success(goose_user_index: usize, mut metric: GooseRequestMetric) {
+    if let Some(user) = GOOSE_USERS.user(goose_user_index).await {
+        metric.error.clear();
+        let _ = user.set_success(&mut metric);
+    }
+}
+
+// Names a request after the path template of its endpoint, e.g.
+// `PUT /_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id`, so that all
+// the requests to an endpoint are aggregated in a single row of the Goose report.
+fn endpoint_name(metadata: &Metadata, request: &http::Request<Bytes>) -> String {
+    let path = request.uri().path();
+    let template = metadata
+        .history
+        .all_paths()
+        .find(|template| path_matches_template(path, template))
+        .unwrap_or(path);
+
+    format!("{} {}", metadata.method, template)
+}
+
+// Compares the segments from the end, since the homeserver URL can have a path
+fn path_matches_template(path: &str, template: &str) -> bool {
+    path.split('/').count() >= template.split('/').count()
+        && path
+            .rsplit('/')
+            .zip(template.rsplit('/'))
+            .all(|(segment, template_segment)| {
+                template_segment.starts_with(':') || segment == template_segment
+            })
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct HttpSettings {
     #[cfg(not(target_arch = "wasm32"))]
@@ -461,6 +648,7 @@ impl HttpSend for reqwest::Client {
         request: http::Request<Bytes>,
         _timeout: Duration,
         goose_user_index: usize,
+        request_name: &str,
     ) -> Result<http::Response<Bytes>, HttpError> {
         #[allow(unused_mut)]
         let mut request = reqwest::Request::try_from(request)?;
@@ -476,8 +664,6 @@ impl HttpSend for reqwest::Client {
         // Goose integration start
         // println!("Got response: {:?}", response);
 
-        let request_name = request.try_clone().unwrap();
-        let mut name = request_name.url().as_str().to_owned();
         // let request_body = reqwest::Request::try_clone(&request).unwrap();
         // let body = request_body.body().unwrap().as_bytes().unwrap().to_vec();
 
@@ -502,55 +688,25 @@ impl HttpSend for reqwest::Client {
         // request_builder = request_builder.body(body);
         // request_builder.body(request.body().unwrap().clone());
 
-        // Fix endpoint naming for reports
-        if let Some(index) = name.find('?') {
-            name.truncate(index);
-        }
-        if let Some(index) = name.find('!') {
-            let (first, last) = name.split_at(index);
-            match last.find('/') {
-                Some(index) => name = first.to_owned() + "_" + &last[index .. last.len()],
-                None => name = first.to_owned() + "_",
-            }
-        }
-        if let Some(index) = name.find('@') {
-            let (first, last) = name.split_at(index);
-            match last.find('/') {
-                Some(index) => name = first.to_owned() + "_" + &last[index .. last.len()],
-                None => name = first.to_owned() + "_",
-            }
-        }
-        if let Some(index) = name.find('$') {
-            let (first, last) = name.split_at(index);
-            match last.find('/') {
-                Some(index) => name = first.to_owned() + "_" + &last[index .. last.len()],
-                None => name = first.to_owned() + "_",
-            }
-        }
-        if let Some(index) = name.find("m.room.message/") {
-            name.truncate(index + "m.room.message/".len());
-            name.push('_');
-        }
-        if let Some(index) = name.find("m.reaction/") {
-            name.truncate(index + "m.reaction/".len());
-            name.push('_');
-        }
-
         let goose_request = GooseRequest::builder()
             // Goose will prepend a host name to this path.
             // .path(&*homeserver)
-            .name(&*name)
+            .name(request_name)
             // .method(method)
             .set_request_builder(request_builder)
             .build();
 
         // println!("Sending goose request {:?}", goose_request);
 
-        // Note: Consider changing to using mutex since a single goose user owns two threads (sync_forever and logic thread)
-        // Also improve error handling
-        let user: &mut GooseUser = unsafe { GOOSE_USERS[goose_user_index].as_mut().unwrap() };
+        // Lent until the response arrives, so that the next request can reuse the user
+        let mut user = GOOSE_USERS
+            .user(goose_user_index)
+            .await
+            .ok_or(HttpError::UnregisteredGooseUser(goose_user_index))?;
+        let result = user.request(goose_request).await;
+        drop(user);
 
-        match user.request(goose_request).await {
+        match result {
             Ok(goose_response) => {
                 // If required in the future, consider adding global response vector for access in scripts.
                 // Easier to maintain than propagating response results through all the various function
@@ -561,28 +717,97 @@ impl HttpSend for reqwest::Client {
 
                 // Goose integration end
 
-                Ok(response_to_http_response(response).await?)
+                // Passed on, so that a failed request can still be marked with its Matrix error
+                let mut response = response_to_http_response(response).await?;
+                response.extensions_mut().insert(goose_response.request);
+
+                Ok(response)
             },
             Err(err) => {
-                // If required in the future, consider adding global error vector for access in scripts.
-                // Easier to maintain than propagating error results through all the various function
-                // signatures
+                // Keep the Goose error, so that callers can tell a canceled request from a failed one
                 println!("Error sending request: {:?}", err);
 
-                match *err {
-                    TransactionError::Reqwest(e) => Err(HttpError::Reqwest(e)),
-                    // For now, sending random error since there is no error mapping between types
-                    _ => Err(HttpError::NotClientRequest),
-
-                    // TransactionError::Url(_) => todo!(),
-                    // TransactionError::RequestFailed { raw_request } => todo!(),
-                    // TransactionError::RequestCanceled { source } => todo!(),
-                    // TransactionError::MetricsFailed { source } => todo!(),
-                    // TransactionError::LoggerFailed { source } => todo!(),
-                    // TransactionError::InvalidMethod { method } => todo!(),
-                }
+                Err(HttpError::from(err))
             },
         }
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use ruma::api::{
+        client::{
+            alias::get_alias, message::send_message_event, state::send_state_event,
+            sync::sync_events,
+        },
+        OutgoingRequest,
+    };
+
+    use super::{endpoint_name, path_matches_template};
+
+    fn request(uri: &str) -> http::Request<Bytes> {
+        http::Request::builder().uri(uri).body(Bytes::new()).unwrap()
+    }
+
+    #[test]
+    fn match_path_from_the_end() {
+        assert!(path_matches_template(
+            "/_matrix/client/v3/rooms/!room:localhost/join",
+            "/_matrix/client/v3/rooms/:room_id/join"
+        ));
+        assert!(path_matches_template("/matrix/_matrix/client/v3/sync", "/_matrix/client/v3/sync"));
+        assert!(!path_matches_template("/_matrix/client/r0/sync", "/_matrix/client/v3/sync"));
+        assert!(!path_matches_template("/v3/sync", "/_matrix/client/v3/sync"));
+    }
+
+    #[test]
+    fn name_prefixed_homeserver_path() {
+        let request = request(
+            "https://example.org/matrix/_matrix/client/v3/rooms/%21room%3Alocalhost/send/m.room.message/1",
+        );
+
+        assert_eq!(
+            endpoint_name(&send_message_event::v3::Request::METADATA, &request),
+            "PUT /_matrix/client/v3/rooms/:room_id/send/:event_type/:txn_id"
+        );
+    }
+
+    #[test]
+    fn name_alias() {
+        let request =
+            request("https://example.org/_matrix/client/v3/directory/room/%23alias%3Alocalhost");
+
+        assert_eq!(
+            endpoint_name(&get_alias::v3::Request::METADATA, &request),
+            "GET /_matrix/client/v3/directory/room/:room_alias"
+        );
+    }
+
+    #[test]
+    fn name_state_key() {
+        let template = "PUT /_matrix/client/v3/rooms/:room_id/state/:event_type/:state_key";
+
+        let request = request(
+            "https://example.org/_matrix/client/v3/rooms/%21room%3Alocalhost/state/m.room.member/%40alice%3Alocalhost",
+        );
+        assert_eq!(endpoint_name(&send_state_event::v3::Request::METADATA, &request), template);
+
+        // An empty state key leaves a trailing slash
+        let request = request(
+            "https://example.org/_matrix/client/v3/rooms/%21room%3Alocalhost/state/m.room.name/",
+        );
+        assert_eq!(endpoint_name(&send_state_event::v3::Request::METADATA, &request), template);
+    }
+
+    #[test]
+    fn name_unknown_path() {
+        let request = request("https://example.org/custom/sync");
+
+        assert_eq!(
+            endpoint_name(&sync_events::v3::Request::METADATA, &request),
+            "GET /custom/sync"
+        );
+    }
+}
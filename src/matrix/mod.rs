@@ -83,8 +83,6 @@ use crate::encryption::Encryption;
 //     Account, Error, Media, RefreshTokenError, Result, RumaApiError,
 // };
 
-use goose::prelude::*;
-
 use crate::matrix::{
     account::Account,
     builder::{GooseClientBuilder, ClientBuildError},
@@ -105,6 +103,7 @@ mod builder;
 pub mod config;
 mod event_handler;
 mod error;
+mod goose_users;
 mod login_builder;
 mod http_client;
 mod media;
@@ -115,8 +114,14 @@ mod sync;
 pub use self::login_builder::SsoLoginBuilder;
 pub use self::{
     // builder::{ClientBuildError, ClientBuilder},
+    error::{Error, HttpError, HttpResult, RumaApiError},
+    event_handler::Ctx,
+    goose_users::{GooseUserRegistry, GOOSE_USERS},
+    http_client::{with_expected_errcodes, with_request_name_prefix},
     login_builder::LoginBuilder,
 };
+pub(crate) use self::http_client::is_expected_errcode;
+pub use matrix_sdk_base::Session;
 
 #[cfg(not(target_arch = "wasm32"))]
 type NotificationHandlerFut = Pin<Box<dyn Future<Output = ()> + Send>>;
@@ -216,15 +221,12 @@ pub struct ClientInner {
     /// the user when any request fails because of an invalid access token
     pub(crate) unknown_token_error_sender: broadcast::Sender<UnknownToken>,
 
-    /// Index of the user in the static table of Goose user references. The
-    /// reference ins not directly stored in this struct because there are too
-    /// many lifetime annotations to maintain and propagates across many types,
-    /// while additionally also involves some code re-writing as well.
+    /// Index of the Goose user sending the requests, as registered in
+    /// [`GOOSE_USERS`]. The user is not directly stored in this struct because
+    /// Goose only lends it to the transaction it is running.
     pub goose_user_index: usize,
 }
 
-pub static mut GOOSE_USERS: Vec<*mut GooseUser> = Vec::new();
-
 
 #[cfg(not(tarpaulin_include))]
 // impl Debug for Client {
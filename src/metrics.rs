@@ -0,0 +1,553 @@
+//! Custom metrics that Goose can't collect from the HTTP requests alone.
+//!
+//! Goose 0.17 has no support for custom metrics, so their summaries are added
+//! to the report with [`crate::report::add_section`].
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use bytesize::ByteSize;
+use once_cell::sync::Lazy;
+
+use crate::{task_sleep, CANCELED};
+
+/// The rate limiting of all the Matrix requests sent by this process.
+pub static RATE_LIMITS: Lazy<RateLimits> = Lazy::new(RateLimits::default);
+
+/// The size of all the Matrix requests sent by this process and of their
+/// responses.
+pub static PAYLOAD_SIZES: Lazy<PayloadSizes> = Lazy::new(PayloadSizes::default);
+
+/// Samples of a custom metric, summarised with percentiles.
+///
+/// All the samples are kept in memory, so this is only meant for metrics
+/// recorded once per message, action or rate limited request. The sizes of
+/// the payloads, recorded for every request, go to a [`SizeHistogram`]
+/// instead.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    samples: Mutex<Vec<f64>>,
+}
+
+impl Histogram {
+    /// Adds a sample.
+    pub fn record(&self, value: f64) {
+        self.samples.lock().unwrap().push(value);
+    }
+
+    /// Returns the number of samples.
+    pub fn len(&self) -> usize {
+        self.samples.lock().unwrap().len()
+    }
+
+    /// Returns whether no sample was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the mean of the samples.
+    pub fn mean(&self) -> Option<f64> {
+        let samples = self.samples.lock().unwrap();
+        if samples.is_empty() {
+            return None;
+        }
+        Some(samples.iter().sum::<f64>() / samples.len() as f64)
+    }
+
+    /// Returns the value below which the given percentage of the samples
+    /// fall, using the nearest-rank method.
+    pub fn percentile(&self, percent: f64) -> Option<f64> {
+        let samples = self.sorted();
+        percentile(&samples, percent)
+    }
+
+    /// Returns the count, mean, p50, p95, p99 and maximum of the samples,
+    /// followed by the given unit.
+    pub fn summary(&self, unit: &str) -> String {
+        let samples = self.sorted();
+        if samples.is_empty() {
+            return "No samples".to_owned();
+        }
+
+        let mean = samples.iter().sum::<f64>() / samples.len() as f64;
+        let mut lines = vec![
+            format!("Count: {}", samples.len()),
+            format!("Min: {:.1} {}", samples[0], unit),
+            format!("Mean: {:.1} {}", mean, unit),
+        ];
+        for percent in [50.0, 95.0, 99.0] {
+            lines.push(format!(
+                "p{}: {:.1} {}",
+                percent,
+                percentile(&samples, percent).unwrap(),
+                unit
+            ));
+        }
+        lines.push(format!("Max: {:.1} {}", samples[samples.len() - 1], unit));
+
+        lines.join("\n")
+    }
+
+    fn sorted(&self) -> Vec<f64> {
+        let mut samples = self.samples.lock().unwrap().clone();
+        samples.sort_by(f64::total_cmp);
+        samples
+    }
+}
+
+fn percentile(sorted: &[f64], percent: f64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let rank = (percent / 100.0 * sorted.len() as f64).ceil() as usize;
+    Some(sorted[rank.clamp(1, sorted.len()) - 1])
+}
+
+/// Byte sizes summarised with percentiles, counted in logarithmic buckets.
+///
+/// Unlike a [`Histogram`], its memory doesn't grow with the number of samples:
+/// there are 8 buckets per doubling of the size, so a percentile is at most 9%
+/// above the actual size.
+#[derive(Debug, Default)]
+pub struct SizeHistogram {
+    stats: Mutex<SizeStats>,
+}
+
+#[derive(Debug, Default)]
+struct SizeStats {
+    buckets: BTreeMap<u32, u64>,
+    count: u64,
+    total: u64,
+    max: u64,
+}
+
+// Buckets per doubling of the size
+const SIZE_BUCKETS_PER_DOUBLING: f64 = 8.0;
+
+impl SizeHistogram {
+    /// Adds a size.
+    pub fn record(&self, size: ByteSize) {
+        let bytes = size.as_u64();
+        let mut stats = self.stats.lock().unwrap();
+        *stats.buckets.entry(size_bucket(bytes)).or_default() += 1;
+        stats.count += 1;
+        stats.total = stats.total.saturating_add(bytes);
+        stats.max = stats.max.max(bytes);
+    }
+
+    /// Returns the number of sizes.
+    pub fn len(&self) -> u64 {
+        self.stats.lock().unwrap().count
+    }
+
+    /// Returns whether no size was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the exact mean of the sizes.
+    pub fn mean(&self) -> Option<ByteSize> {
+        let stats = self.stats.lock().unwrap();
+        if stats.count == 0 {
+            return None;
+        }
+        Some(ByteSize(stats.total / stats.count))
+    }
+
+    /// Returns the upper bound of the bucket below which the given
+    /// percentage of the sizes fall, using the nearest-rank method.
+    pub fn percentile(&self, percent: f64) -> Option<ByteSize> {
+        let stats = self.stats.lock().unwrap();
+        if stats.count == 0 {
+            return None;
+        }
+
+        let rank = ((percent / 100.0 * stats.count as f64).ceil() as u64).clamp(1, stats.count);
+        let mut seen = 0;
+        stats.buckets.iter().find_map(|(&bucket, &count)| {
+            seen += count;
+            (seen >= rank).then(|| ByteSize(size_bucket_bound(bucket).min(stats.max)))
+        })
+    }
+
+    /// Returns the exact maximum of the sizes.
+    pub fn max(&self) -> Option<ByteSize> {
+        let stats = self.stats.lock().unwrap();
+        (stats.count > 0).then(|| ByteSize(stats.max))
+    }
+}
+
+// Empty payloads get a bucket of their own, the others are bucketed by the
+// logarithm of their size
+fn size_bucket(bytes: u64) -> u32 {
+    if bytes == 0 {
+        return 0;
+    }
+    ((bytes as f64).log2() * SIZE_BUCKETS_PER_DOUBLING).floor() as u32 + 1
+}
+
+// Returns the largest size in the given bucket
+fn size_bucket_bound(bucket: u32) -> u64 {
+    if bucket == 0 {
+        return 0;
+    }
+    (bucket as f64 / SIZE_BUCKETS_PER_DOUBLING).exp2().ceil() as u64 - 1
+}
+
+/// Latency and failure rate of named user actions, such as "open a room",
+/// that take several requests.
+///
+/// Goose only times the individual requests, so scenario code wraps the
+/// requests of an action in an [`ActionScope`] to time them as a whole.
+#[derive(Debug, Default)]
+pub struct ActionMetrics {
+    actions: Mutex<BTreeMap<String, ActionStats>>,
+}
+
+#[derive(Debug, Default)]
+struct ActionStats {
+    durations: Histogram,
+    failures: usize,
+}
+
+impl ActionMetrics {
+    /// Starts timing an action, until the returned scope is dropped.
+    pub fn start(&self, name: &str) -> ActionScope<'_> {
+        ActionScope {
+            metrics: self,
+            name: name.to_owned(),
+            started: Instant::now(),
+            excluded: Duration::ZERO,
+            failed: false,
+        }
+    }
+
+    /// Times the given future as an action, which fails if it returns an
+    /// error.
+    pub async fn scope<T, E>(
+        &self,
+        name: &str,
+        action: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let mut scope = self.start(name);
+        let result = action.await;
+        if result.is_err() {
+            scope.fail();
+        }
+        result
+    }
+
+    fn record(&self, name: &str, duration: Duration, failed: bool) {
+        let mut actions = self.actions.lock().unwrap();
+        let stats = actions.entry(name.to_owned()).or_default();
+        stats.durations.record(duration.as_secs_f64() * 1000.0);
+        if failed {
+            stats.failures += 1;
+        }
+    }
+
+    /// Returns one line per action with its count, failure rate and
+    /// latency percentiles.
+    pub fn summary(&self) -> String {
+        let actions = self.actions.lock().unwrap();
+        if actions.is_empty() {
+            return "No actions".to_owned();
+        }
+
+        actions
+            .iter()
+            .map(|(name, stats)| {
+                let count = stats.durations.len();
+                format!(
+                    "{}: {} runs, {} failed ({:.1}%), mean {:.1} ms, p50 {:.1} ms, p95 {:.1} ms, p99 {:.1} ms, max {:.1} ms",
+                    name,
+                    count,
+                    stats.failures,
+                    stats.failures as f64 / count as f64 * 100.0,
+                    stats.durations.mean().unwrap(),
+                    stats.durations.percentile(50.0).unwrap(),
+                    stats.durations.percentile(95.0).unwrap(),
+                    stats.durations.percentile(99.0).unwrap(),
+                    stats.durations.percentile(100.0).unwrap(),
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/// A running action, recorded in its [`ActionMetrics`] when dropped.
+#[derive(Debug)]
+pub struct ActionScope<'a> {
+    metrics: &'a ActionMetrics,
+    name: String,
+    started: Instant,
+    excluded: Duration,
+    failed: bool,
+}
+
+impl ActionScope<'_> {
+    /// Marks the action as failed, e.g. when one of its requests failed.
+    pub fn fail(&mut self) {
+        self.failed = true;
+    }
+
+    /// Runs the given future without counting its duration, e.g. to leave
+    /// out the think time of the user in the middle of an action.
+    pub async fn exclude<F: Future>(&mut self, future: F) -> F::Output {
+        let started = Instant::now();
+        let output = future.await;
+        self.excluded += started.elapsed();
+        output
+    }
+}
+
+impl Drop for ActionScope<'_> {
+    fn drop(&mut self) {
+        let duration = self.started.elapsed().saturating_sub(self.excluded);
+        self.metrics.record(&self.name, duration, self.failed);
+    }
+}
+
+/// How often and how long the homeserver rate limited the requests, per
+/// endpoint.
+///
+/// Requests answered with `M_LIMIT_EXCEEDED` are retried after a backoff
+/// honoring the delay the homeserver asked for in `retry_after_ms`. The time
+/// until the retry is otherwise invisible in the request timings of Goose.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    hits: AtomicUsize,
+    endpoints: Mutex<BTreeMap<String, RateLimitStats>>,
+}
+
+#[derive(Debug, Default)]
+struct RateLimitStats {
+    hits: usize,
+    waited: Duration,
+    delays: Histogram,
+}
+
+impl RateLimits {
+    /// Records a rate limited request to the given endpoint, with the delay
+    /// the homeserver asked for.
+    pub fn record(&self, endpoint: &str, retry_after: Option<Duration>) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let stats = endpoints.entry(endpoint.to_owned()).or_default();
+        stats.hits += 1;
+        if let Some(retry_after) = retry_after {
+            stats.delays.record(retry_after.as_secs_f64() * 1000.0);
+        }
+    }
+
+    /// Records the time a rate limited request to the given endpoint waited,
+    /// from its response to its retry.
+    ///
+    /// Requests which aren't retried, e.g. when reaching the retry limit,
+    /// don't wait.
+    pub fn record_wait(&self, endpoint: &str, waited: Duration) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        endpoints.entry(endpoint.to_owned()).or_default().waited += waited;
+    }
+
+    /// Returns the number of rate limited requests so far.
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the time spent waiting for the rate limits so far.
+    pub fn waited(&self) -> Duration {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints.values().map(|stats| stats.waited).sum()
+    }
+
+    /// Returns the totals, the distribution of the delays the homeserver asked
+    /// for, and one line per rate limited endpoint.
+    pub fn summary(&self) -> String {
+        let endpoints = self.endpoints.lock().unwrap();
+        if endpoints.is_empty() {
+            return "No rate limited requests".to_owned();
+        }
+
+        let delays = Histogram::default();
+        let mut waited = Duration::ZERO;
+        for stats in endpoints.values() {
+            for delay in stats.delays.sorted() {
+                delays.record(delay);
+            }
+            waited += stats.waited;
+        }
+
+        let mut lines = vec![
+            format!("Rate limited requests: {}", self.hits()),
+            format!("Time waited: {:.1} s", waited.as_secs_f64()),
+            String::new(),
+            "Delays asked for:".to_owned(),
+            delays.summary("ms"),
+            String::new(),
+        ];
+        for (endpoint, stats) in endpoints.iter() {
+            let mut line = format!(
+                "{}: {} hits, waited {:.1} s",
+                endpoint,
+                stats.hits,
+                stats.waited.as_secs_f64()
+            );
+            if let (Some(p50), Some(max)) = (
+                stats.delays.percentile(50.0),
+                stats.delays.percentile(100.0),
+            ) {
+                line.push_str(&format!(", p50 delay {:.1} ms, max {:.1} ms", p50, max));
+            }
+            lines.push(line);
+        }
+
+        lines.join("\n")
+    }
+
+    /// Prints the number of rate limited requests every `interval` until the
+    /// load test is canceled, e.g. along with the `--running-metrics` of Goose.
+    pub fn print_running(&'static self, interval: Duration) {
+        tokio::spawn(async move {
+            loop {
+                task_sleep(interval.as_secs_f64(), true).await;
+                if *CANCELED.read().await {
+                    break;
+                }
+                println!(
+                    "Rate limited requests: {} ({:.1} s waited)",
+                    self.hits(),
+                    self.waited().as_secs_f64()
+                );
+            }
+        });
+    }
+}
+
+/// Bytes sent and received per endpoint, e.g. to follow the growth of the
+/// `/sync` responses as the rooms fill up.
+///
+/// Goose only records the duration of the requests, not the size of their
+/// payloads.
+#[derive(Debug, Default)]
+pub struct PayloadSizes {
+    // Every request records its sizes, so each endpoint has locks of its own
+    endpoints: RwLock<BTreeMap<String, Arc<EndpointSizes>>>,
+}
+
+#[derive(Debug, Default)]
+struct EndpointSizes {
+    sent: SizeHistogram,
+    received: SizeHistogram,
+}
+
+impl PayloadSizes {
+    /// Records the size of the body of a request to the given endpoint, and
+    /// of its response unless it failed.
+    pub fn record(&self, endpoint: &str, request_size: ByteSize, response_size: Option<ByteSize>) {
+        let sizes = self.endpoint(endpoint);
+        sizes.sent.record(request_size);
+        if let Some(response_size) = response_size {
+            sizes.received.record(response_size);
+        }
+    }
+
+    fn endpoint(&self, endpoint: &str) -> Arc<EndpointSizes> {
+        if let Some(sizes) = self.endpoints.read().unwrap().get(endpoint) {
+            return Arc::clone(sizes);
+        }
+        let mut endpoints = self.endpoints.write().unwrap();
+        Arc::clone(endpoints.entry(endpoint.to_owned()).or_default())
+    }
+
+    /// Returns whether no request was recorded.
+    pub fn is_empty(&self) -> bool {
+        self.endpoints.read().unwrap().is_empty()
+    }
+
+    /// Returns the mean, percentiles and maximum of the bytes sent and
+    /// received, one line each per endpoint.
+    pub fn summary(&self) -> String {
+        let endpoints = self.endpoints.read().unwrap();
+        if endpoints.is_empty() {
+            return "No requests".to_owned();
+        }
+
+        let mut lines = Vec::new();
+        for (endpoint, sizes) in endpoints.iter() {
+            lines.push(format!("{}:", endpoint));
+            lines.push(format!("  out: {}", size_summary(&sizes.sent)));
+            lines.push(format!("  in: {}", size_summary(&sizes.received)));
+        }
+
+        lines.join("\n")
+    }
+}
+
+fn size_summary(sizes: &SizeHistogram) -> String {
+    let mean = match sizes.mean() {
+        Some(mean) => mean,
+        None => return "no responses".to_owned(),
+    };
+
+    let bytes = |size: Option<ByteSize>| size.unwrap().to_string_as(true);
+    format!(
+        "{} requests, mean {}, p50 {}, p95 {}, p99 {}, max {}",
+        sizes.len(),
+        bytes(Some(mean)),
+        bytes(sizes.percentile(50.0)),
+        bytes(sizes.percentile(95.0)),
+        bytes(sizes.percentile(99.0)),
+        bytes(sizes.max()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bytesize::ByteSize;
+
+    use super::SizeHistogram;
+
+    #[test]
+    fn size_percentiles() {
+        let sizes = SizeHistogram::default();
+        assert_eq!(sizes.percentile(50.0), None);
+
+        for bytes in 1..=1000 {
+            sizes.record(ByteSize(bytes));
+        }
+
+        assert_eq!(sizes.len(), 1000);
+        assert_eq!(sizes.mean(), Some(ByteSize(500)));
+        assert_eq!(sizes.max(), Some(ByteSize(1000)));
+        for (percent, exact) in [(50.0, 500), (95.0, 950), (99.0, 990)] {
+            let bytes = sizes.percentile(percent).unwrap().as_u64();
+            assert!(
+                bytes >= exact && bytes as f64 <= exact as f64 * 1.1,
+                "p{percent}: {bytes}"
+            );
+        }
+        assert_eq!(sizes.percentile(100.0), Some(ByteSize(1000)));
+    }
+
+    #[test]
+    fn size_buckets_are_bounded() {
+        let sizes = SizeHistogram::default();
+        for bytes in 0..100_000 {
+            sizes.record(ByteSize(bytes * 1000));
+        }
+
+        assert!(sizes.stats.lock().unwrap().buckets.len() <= 200);
+        assert_eq!(sizes.percentile(0.0), Some(ByteSize(0)));
+    }
+}
@@ -0,0 +1,74 @@
+use std::{fs, io, sync::Mutex};
+
+use goose::config::GooseConfiguration;
+use once_cell::sync::Lazy;
+
+use crate::metrics::{PAYLOAD_SIZES, RATE_LIMITS};
+
+/// A block of load test output that Goose doesn't know about, such as the
+/// configuration a binary was run with or metrics collected outside of Goose.
+#[derive(Debug, Clone)]
+pub struct ReportSection {
+    pub title: String,
+    pub body: String,
+}
+
+static SECTIONS: Lazy<Mutex<Vec<ReportSection>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Queues a section to be written once the load test finishes.
+pub fn add_section(title: impl Into<String>, body: impl Into<String>) {
+    SECTIONS.lock().unwrap().push(ReportSection {
+        title: title.into(),
+        body: body.into(),
+    });
+}
+
+/// Prints all queued sections and appends them to the html report, if one
+/// was requested with `--report-file`.
+///
+/// Goose only writes its report at the end of [`GooseAttack::execute`], so
+/// this has to be called after the attack has completed.
+///
+/// [`GooseAttack::execute`]: goose::GooseAttack::execute
+pub fn write(configuration: &GooseConfiguration) -> io::Result<()> {
+    // Matrix requests of every binary can be rate limited
+    if RATE_LIMITS.hits() > 0 {
+        add_section("Rate limits", RATE_LIMITS.summary());
+    }
+    if !PAYLOAD_SIZES.is_empty() {
+        add_section("Payload sizes", PAYLOAD_SIZES.summary());
+    }
+
+    let sections = SECTIONS.lock().unwrap();
+
+    for section in sections.iter() {
+        println!("\n === {} ===\n{}", section.title, section.body);
+    }
+
+    if configuration.report_file.is_empty() || sections.is_empty() {
+        return Ok(());
+    }
+
+    let mut html = String::new();
+    for section in sections.iter() {
+        html.push_str(&format!(
+            "<div class=\"matrix\">\n<h2>{}</h2>\n<pre>{}</pre>\n</div>\n",
+            escape_html(&section.title),
+            escape_html(&section.body)
+        ));
+    }
+
+    let mut report = fs::read_to_string(&configuration.report_file)?;
+    match report.rfind("</body>") {
+        Some(index) => report.insert_str(index, &html),
+        None => report.push_str(&html),
+    }
+
+    fs::write(&configuration.report_file, report)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,39 @@
+//! Reproducible randomness for load tests.
+//!
+//! Every Goose user draws from its own RNG, derived from the run seed and the
+//! user's `weighted_users_index`, so that the same seed replays the same
+//! sequence of actions, rooms and messages for each user.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use once_cell::sync::{Lazy, OnceCell};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+static SEED: OnceCell<u64> = OnceCell::new();
+static USER_RNGS: Lazy<Mutex<HashMap<usize, StdRng>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Sets the seed of the run, picking a random one if none is given, and
+/// returns it so it can be reported. Only the first call has an effect.
+pub fn set_seed(seed: Option<u64>) -> u64 {
+    *SEED.get_or_init(|| seed.unwrap_or_else(|| rand::thread_rng().gen()))
+}
+
+/// Returns the seed of the run, picking a random one if none was set.
+pub fn seed() -> u64 {
+    set_seed(None)
+}
+
+/// Creates the RNG of the user at `index` for the given seed.
+pub fn user_rng(seed: u64, index: usize) -> StdRng {
+    // Spread the user indexes so that neighbouring seeds don't share streams
+    StdRng::seed_from_u64(seed ^ (index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+/// Calls `f` with the RNG of the user at `index`, creating it on first use.
+///
+/// The RNG is locked while `f` runs, so `f` must not block or await.
+pub fn with_user_rng<T>(index: usize, f: impl FnOnce(&mut StdRng) -> T) -> T {
+    let mut rngs = USER_RNGS.lock().unwrap();
+    let rng = rngs.entry(index).or_insert_with(|| user_rng(seed(), index));
+    f(rng)
+}
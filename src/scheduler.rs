@@ -0,0 +1,230 @@
+//! Weighted, non-deterministic scheduling of user actions.
+//!
+//! The Goose task scheduler is insufficient for our use-case:
+//!   1. Transaction ordering for a given scenario is deterministic and not random
+//!   2. Scenario scheduling can be set to 'random', but Goose allocates a set of users
+//!      only at start to a given scenario that ALWAYS runs the transactions within its
+//!      own scenario for the remainder of the program.
+//!
+//! Thus to replicate similar behavior to Locust, binaries register their actions with a
+//! [`Scheduler`] and run it from a single Goose transaction.
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use goose::prelude::*;
+use once_cell::sync::Lazy;
+use tokio::time::{Duration, Instant};
+use weighted_rand::builder::*;
+
+use crate::{rng, task_sleep, workload::Persona, CANCELED};
+
+/// An async function performing a single user action, in the same shape as a
+/// Goose transaction function.
+pub type ActionFunction = Arc<
+    dyn for<'r> Fn(
+            &'r mut GooseUser,
+        ) -> Pin<Box<dyn Future<Output = TransactionResult> + Send + 'r>>
+        + Send
+        + Sync,
+>;
+
+/// `action!(foo)` expands to `Action::new("foo", ...)`, where `foo` is an
+/// async function taking a `&mut GooseUser`.
+#[macro_export]
+macro_rules! action {
+    ($action_func:ident) => {
+        $crate::scheduler::Action::new(
+            stringify!($action_func),
+            std::sync::Arc::new(move |s| std::boxed::Box::pin($action_func(s))),
+        )
+    };
+}
+
+/// A named user action and how often it should be picked.
+#[derive(Clone)]
+pub struct Action {
+    pub name: String,
+    pub weight: u32,
+    function: ActionFunction,
+}
+
+impl Action {
+    /// Creates an action with a weight of one.
+    pub fn new(name: &str, function: ActionFunction) -> Self {
+        Action {
+            name: name.to_owned(),
+            weight: 1,
+            function,
+        }
+    }
+
+    /// Sets the relative weight of the action. Actions with a weight of zero
+    /// are never picked.
+    pub fn set_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+}
+
+/// Start of the attack, used to stop scheduling once `--run-time` expires.
+static ATTACK_START: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Picks weighted random actions in a loop until the load test is canceled
+/// or its run time expires.
+#[derive(Clone)]
+pub struct Scheduler {
+    actions: Vec<Action>,
+    wait_time: f64,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    /// Creates a scheduler without actions, waiting 0.1s between actions.
+    pub fn new() -> Self {
+        Scheduler {
+            actions: Vec::new(),
+            wait_time: 0.1,
+        }
+    }
+
+    /// Adds an action to the scheduler.
+    pub fn register_action(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    /// Sets the time in seconds to sleep between two actions.
+    pub fn set_wait_time(mut self, wait_time: f64) -> Self {
+        self.wait_time = wait_time;
+        self
+    }
+
+    /// Overrides the action weights with the ones from the given workload
+    /// persona. Actions the persona doesn't perform are never picked.
+    pub fn set_persona(mut self, persona: &Persona) -> Self {
+        for action in self.actions.iter_mut() {
+            action.weight = persona
+                .action(&action.name)
+                .map_or(0, |config| config.weight);
+        }
+        self
+    }
+
+    /// Returns the names of all registered actions.
+    pub fn action_names(&self) -> Vec<&str> {
+        self.actions
+            .iter()
+            .map(|action| action.name.as_str())
+            .collect()
+    }
+
+    async fn is_finished(runtime: Option<Duration>) -> bool {
+        // Drop lock after checking canceled status
+        *CANCELED.read().await || runtime.map_or(false, |runtime| ATTACK_START.elapsed() > runtime)
+    }
+
+    /// Runs actions for the given user until the load test is canceled or
+    /// its run time expires. Meant to be called from a Goose transaction.
+    pub async fn run(&self, user: &mut GooseUser) -> TransactionResult {
+        // Goose seems to internally convert the '--run-time' time string to seconds
+        let runtime = user
+            .config
+            .run_time
+            .parse::<u64>()
+            .ok()
+            .map(Duration::from_secs);
+
+        // Goose seems to re-run the scheduler transaction upon the
+        // GooseAttack phase decrease...
+        if Self::is_finished(runtime).await {
+            return Ok(());
+        }
+
+        let weights: Vec<u32> = self.actions.iter().map(|action| action.weight).collect();
+        if weights.iter().all(|weight| *weight == 0) {
+            return Ok(());
+        }
+        let action_gen = WalkerTableBuilder::new(&weights).build();
+
+        loop {
+            // Goose is unable to terminate users upon switching to the decrease phase
+            if Self::is_finished(runtime).await {
+                break;
+            }
+
+            let index =
+                rng::with_user_rng(user.weighted_users_index, |rng| action_gen.next_rng(rng));
+            let _ = (self.actions[index].function)(user).await;
+
+            task_sleep(self.wait_time, true).await;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use goose::prelude::*;
+
+    use super::Scheduler;
+    use crate::workload::WorkloadConfig;
+
+    async fn send_text(_user: &mut GooseUser) -> TransactionResult {
+        Ok(())
+    }
+
+    async fn look_at_room(_user: &mut GooseUser) -> TransactionResult {
+        Ok(())
+    }
+
+    async fn go_afk(_user: &mut GooseUser) -> TransactionResult {
+        Ok(())
+    }
+
+    fn weights(scheduler: &Scheduler) -> Vec<(&str, u32)> {
+        scheduler
+            .actions
+            .iter()
+            .map(|action| (action.name.as_str(), action.weight))
+            .collect()
+    }
+
+    #[test]
+    fn set_persona() {
+        let workload: WorkloadConfig = toml::from_str(
+            r#"
+            [actions.send_text]
+            weight = 6
+
+            [actions.look_at_room]
+            weight = 4
+
+            [personas.lurker]
+            actions.send_text = { weight = 1 }
+            "#,
+        )
+        .unwrap();
+        let scheduler = Scheduler::new()
+            .register_action(action!(send_text).set_weight(2))
+            .register_action(action!(look_at_room).set_weight(2))
+            .register_action(action!(go_afk).set_weight(2));
+        assert_eq!(
+            weights(&scheduler),
+            [("send_text", 2), ("look_at_room", 2), ("go_afk", 2)]
+        );
+
+        let lurker = scheduler.clone().set_persona(&workload.personas()[0]);
+
+        // Actions missing from the workload are never picked
+        assert_eq!(
+            weights(&lurker),
+            [("send_text", 1), ("look_at_room", 4), ("go_afk", 0)]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Cache of the access tokens of the load test users.
+//!
+//! Logging in creates a new device on the homeserver, so repeated runs would
+//! leave thousands of devices behind. Instead the binaries save the sessions
+//! they log in with and restore them on the next run, only logging in again
+//! when the homeserver no longer knows the access token.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use ruma::api::client::{account::register, error::ErrorKind};
+
+use crate::matrix::{Error, GooseMatrixClient, Session};
+
+/// Default path of the session cache.
+pub const SESSIONS_FILE: &str = "sessions.json";
+
+/// How a user ended up logged in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginKind {
+    /// The cached session was still valid.
+    Restored,
+    /// The user logged in with its password, creating a new device.
+    Password,
+}
+
+/// Sessions of the load test users, keyed by username and shared by all
+/// the Goose users of a binary.
+#[derive(Debug, Default)]
+pub struct SessionCache {
+    path: PathBuf,
+    sessions: Mutex<BTreeMap<String, Session>>,
+}
+
+impl SessionCache {
+    /// Reads the cache from the given file, starting empty if it doesn't
+    /// exist or can't be read.
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let sessions = match File::open(path) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(sessions) => sessions,
+                Err(err) => {
+                    println!("Ignoring invalid session cache {}: {}", path.display(), err);
+                    BTreeMap::new()
+                }
+            },
+            Err(_) => BTreeMap::new(),
+        };
+
+        SessionCache {
+            path: path.to_owned(),
+            sessions: Mutex::new(sessions),
+        }
+    }
+
+    /// Writes the cache back to the file it was loaded from.
+    pub fn save(&self) -> io::Result<()> {
+        let file = File::create(&self.path)?;
+        let sessions = self.sessions.lock().unwrap();
+        Ok(serde_json::to_writer_pretty(
+            BufWriter::new(file),
+            &*sessions,
+        )?)
+    }
+
+    /// Returns the number of cached sessions.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    /// Returns whether no session is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cached session of the given user.
+    pub fn get(&self, username: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().get(username).cloned()
+    }
+
+    /// Caches the current session of the client, if it is logged in.
+    pub fn insert_from(&self, username: &str, client: &GooseMatrixClient) {
+        if let Some(session) = client.session() {
+            self.insert(username, session);
+        }
+    }
+
+    /// Caches the session of a user who just registered, unless the
+    /// registration didn't log it in.
+    pub fn insert_registered(&self, username: &str, response: &register::v3::Response) {
+        if let (Some(access_token), Some(device_id)) = (&response.access_token, &response.device_id)
+        {
+            self.insert(
+                username,
+                Session {
+                    access_token: access_token.to_owned(),
+                    refresh_token: response.refresh_token.to_owned(),
+                    user_id: response.user_id.to_owned(),
+                    device_id: device_id.to_owned(),
+                },
+            );
+        }
+    }
+
+    /// Caches the session of the given user.
+    pub fn insert(&self, username: &str, session: Session) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(username.to_owned(), session);
+    }
+
+    /// Drops the session of the given user, e.g. after logging it out.
+    pub fn remove(&self, username: &str) -> Option<Session> {
+        self.sessions.lock().unwrap().remove(username)
+    }
+
+    /// Logs the client in, restoring the cached session of the user when the
+    /// homeserver still accepts it and logging in with the password otherwise.
+    ///
+    /// The session in use afterwards is cached, to be saved with
+    /// [`SessionCache::save`].
+    pub async fn login(
+        &self,
+        client: &GooseMatrixClient,
+        username: &str,
+        password: &str,
+    ) -> Result<LoginKind, Error> {
+        if let Some(session) = self.get(username) {
+            client.restore_session(session).await?;
+
+            // Make sure the access token is still valid before using it
+            match client.whoami().await {
+                Ok(_) => return Ok(LoginKind::Restored),
+                Err(err) => match err.client_api_error_kind() {
+                    Some(ErrorKind::UnknownToken { .. }) => {
+                        println!("[{}] Cached session expired, logging in", username);
+                        self.remove(username);
+                    }
+                    _ => return Err(err.into()),
+                },
+            }
+        }
+
+        client.login_username(username, password).send().await?;
+        self.insert_from(username, client);
+
+        Ok(LoginKind::Password)
+    }
+}
@@ -0,0 +1,469 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use rand::Rng;
+use rand_distr::{Distribution, Exp, LogNormal, Uniform};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Errors that can happen while loading a workload file.
+#[derive(Error, Debug)]
+pub enum WorkloadError {
+    /// The workload file could not be read.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// The workload file is not valid TOML.
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+
+    /// The workload file is not valid JSON.
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    /// The workload file was parsed but describes an invalid workload.
+    #[error("invalid workload: {0}")]
+    Invalid(String),
+}
+
+/// A random distribution used to pick think-times (in seconds) and message
+/// lengths (in words).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "distribution", rename_all = "lowercase")]
+pub enum DistributionConfig {
+    /// Always the same value.
+    Constant { value: f64 },
+    /// Uniformly distributed between `min` and `max`.
+    Uniform { min: f64, max: f64 },
+    /// Exponentially distributed with rate `lambda`, i.e. mean `1 / lambda`.
+    Exp { lambda: f64 },
+    /// Log-normally distributed with parameters `mu` and `sigma`.
+    LogNormal { mu: f64, sigma: f64 },
+}
+
+impl DistributionConfig {
+    fn validate(&self) -> Result<(), String> {
+        let valid = match *self {
+            Self::Constant { value } => value.is_finite() && value >= 0.0,
+            Self::Uniform { min, max } => {
+                min.is_finite() && max.is_finite() && min >= 0.0 && min <= max
+            }
+            Self::Exp { lambda } => lambda.is_finite() && lambda > 0.0,
+            Self::LogNormal { mu, sigma } => mu.is_finite() && sigma.is_finite() && sigma >= 0.0,
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("invalid distribution parameters {:?}", self))
+        }
+    }
+
+    /// Draws a single value from the distribution.
+    ///
+    /// The parameters are checked when the workload is loaded, so this never
+    /// fails for a validated workload.
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> f64 {
+        match *self {
+            Self::Constant { value } => value,
+            Self::Uniform { min, max } => Uniform::new_inclusive(min, max).sample(rng),
+            Self::Exp { lambda } => Exp::new(lambda).unwrap().sample(rng),
+            Self::LogNormal { mu, sigma } => LogNormal::new(mu, sigma).unwrap().sample(rng),
+        }
+    }
+}
+
+/// How often a user action is picked and how long it takes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActionConfig {
+    /// Relative weight of the action in the scheduler.
+    pub weight: u32,
+    /// Time spent idle while performing the action, e.g. typing or being AFK.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub think_time: Option<DistributionConfig>,
+    /// Number of words in the messages the action sends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_length: Option<DistributionConfig>,
+}
+
+/// The settings of an action a persona does differently from the top level
+/// of the workload. Settings left out are taken from the top level action.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ActionOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub weight: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub think_time: Option<DistributionConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_length: Option<DistributionConfig>,
+}
+
+impl ActionOverride {
+    // Applies the override on top of the given top level action
+    fn apply(&self, base: Option<&ActionConfig>) -> ActionConfig {
+        ActionConfig {
+            weight: self
+                .weight
+                .or_else(|| base.map(|action| action.weight))
+                .unwrap_or(0),
+            think_time: self
+                .think_time
+                .clone()
+                .or_else(|| base.and_then(|action| action.think_time.clone())),
+            message_length: self
+                .message_length
+                .clone()
+                .or_else(|| base.and_then(|action| action.message_length.clone())),
+        }
+    }
+}
+
+/// Which of its joined rooms a user prefers to act in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomPreference {
+    /// Every joined room is equally likely.
+    #[default]
+    Any,
+    /// Rooms with few members are more likely, like a DM-heavy user.
+    Small,
+    /// Rooms with many members are more likely, like a community moderator.
+    Large,
+    /// Always the biggest room from `rooms_created.json`, like an announcement
+    /// bot. Users who aren't in it fall back to `large`.
+    Biggest,
+}
+
+/// A group of users sharing the same behaviour, e.g. lurkers or bots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PersonaConfig {
+    /// Share of the users given this persona, relative to the other personas.
+    /// Users with a persona set in `users.csv` are not counted, see
+    /// [`WorkloadConfig::assign_personas`].
+    #[serde(default = "default_ratio")]
+    pub ratio: u32,
+    /// Which rooms users with this persona favour.
+    #[serde(default)]
+    pub rooms: RoomPreference,
+    /// Actions overriding the ones from the top level of the workload.
+    #[serde(default)]
+    pub actions: BTreeMap<String, ActionOverride>,
+}
+
+fn default_ratio() -> u32 {
+    1
+}
+
+/// A persona with the workload defaults applied, ready to be scheduled.
+#[derive(Debug, Clone)]
+pub struct Persona {
+    pub name: String,
+    pub ratio: u32,
+    pub rooms: RoomPreference,
+    pub actions: BTreeMap<String, ActionConfig>,
+}
+
+impl Persona {
+    /// Returns the configuration of the given action, if the persona performs
+    /// it.
+    pub fn action(&self, name: &str) -> Option<&ActionConfig> {
+        self.actions.get(name)
+    }
+}
+
+/// Name of the persona used when a workload doesn't define any.
+pub const DEFAULT_PERSONA: &str = "default";
+
+/// Weights and timings of the user actions performed during a load test.
+///
+/// Workloads are read from TOML or JSON files (picked by the file extension)
+/// so they can be tuned without rebuilding the binaries:
+///
+/// ```toml
+/// [actions.send_text]
+/// weight = 6
+/// think_time = { distribution = "exp", lambda = 0.2 }
+/// message_length = { distribution = "lognormal", mu = 1.0, sigma = 1.0 }
+///
+/// [personas.lurker]
+/// ratio = 3
+/// rooms = "large"
+/// actions.send_text = { weight = 1 }
+/// ```
+///
+/// The top level actions are shared by all personas, which only list the
+/// actions they do differently, and only the settings that differ: the
+/// lurker above keeps the think time and message length of the top level
+/// `send_text`. Without personas, every user gets the top level actions.
+/// Actions that aren't listed are never picked.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorkloadConfig {
+    #[serde(default)]
+    pub actions: BTreeMap<String, ActionConfig>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub personas: BTreeMap<String, PersonaConfig>,
+}
+
+impl WorkloadConfig {
+    /// Reads a workload from the given file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, WorkloadError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => Ok(serde_json::from_str(&contents)?),
+            _ => Ok(toml::from_str(&contents)?),
+        }
+    }
+
+    /// Checks that the workload only refers to the given actions, that every
+    /// persona can pick at least one of them and that all distributions are
+    /// valid.
+    pub fn validate(&self, known_actions: &[&str]) -> Result<(), WorkloadError> {
+        for (name, persona) in &self.personas {
+            for (action_name, action) in &persona.actions {
+                if action.weight.is_none() && !self.actions.contains_key(action_name) {
+                    return Err(WorkloadError::Invalid(format!(
+                        "action '{}' in persona '{}' needs a weight, as it isn't at the top level",
+                        action_name, name
+                    )));
+                }
+            }
+        }
+
+        for persona in self.personas() {
+            for (name, action) in &persona.actions {
+                if !known_actions.contains(&name.as_str()) {
+                    return Err(WorkloadError::Invalid(format!(
+                        "unknown action '{}' in persona '{}', expected one of {:?}",
+                        name, persona.name, known_actions
+                    )));
+                }
+
+                for distribution in [&action.think_time, &action.message_length]
+                    .into_iter()
+                    .flatten()
+                {
+                    distribution.validate().map_err(|err| {
+                        WorkloadError::Invalid(format!(
+                            "action '{}' in persona '{}': {}",
+                            name, persona.name, err
+                        ))
+                    })?;
+                }
+            }
+
+            if persona.actions.values().all(|action| action.weight == 0) {
+                return Err(WorkloadError::Invalid(format!(
+                    "persona '{}' needs at least one action with a non-zero weight",
+                    persona.name
+                )));
+            }
+        }
+
+        if self.personas().iter().all(|persona| persona.ratio == 0) {
+            return Err(WorkloadError::Invalid(
+                "at least one persona needs a non-zero ratio".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns all personas with the top level actions applied, sorted by
+    /// name. A workload without personas has a single [`DEFAULT_PERSONA`].
+    pub fn personas(&self) -> Vec<Persona> {
+        if self.personas.is_empty() {
+            return vec![Persona {
+                name: DEFAULT_PERSONA.to_owned(),
+                ratio: 1,
+                rooms: RoomPreference::Any,
+                actions: self.actions.clone(),
+            }];
+        }
+
+        self.personas
+            .iter()
+            .map(|(name, config)| {
+                let mut actions = self.actions.clone();
+                for (name, action) in &config.actions {
+                    let merged = action.apply(self.actions.get(name));
+                    actions.insert(name.to_owned(), merged);
+                }
+
+                Persona {
+                    name: name.to_owned(),
+                    ratio: config.ratio,
+                    rooms: config.rooms,
+                    actions,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the persona of every user, given the persona each of them may
+    /// have set explicitly, e.g. in `users.csv`.
+    ///
+    /// The other users are spread over the personas according to their
+    /// ratios, skipping the users with an explicit persona, so that these
+    /// don't skew the ratios.
+    pub fn assign_personas(&self, explicit: &[Option<String>]) -> Vec<String> {
+        let mut slot = 0;
+        explicit
+            .iter()
+            .map(|persona| match persona {
+                Some(name) => name.to_owned(),
+                None => {
+                    slot += 1;
+                    self.assign_persona(slot - 1).to_owned()
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the name of the persona given to the user in the given slot
+    /// according to the persona ratios, spreading the personas evenly over
+    /// the slots.
+    pub fn assign_persona(&self, slot: usize) -> &str {
+        let total: usize = self
+            .personas
+            .values()
+            .map(|persona| persona.ratio as usize)
+            .sum();
+        if total == 0 {
+            return DEFAULT_PERSONA;
+        }
+
+        let mut slot = slot % total;
+        for (name, persona) in &self.personas {
+            if slot < persona.ratio as usize {
+                return name;
+            }
+            slot -= persona.ratio as usize;
+        }
+
+        DEFAULT_PERSONA
+    }
+
+    /// Renders the workload as TOML, e.g. to record it in the report.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self)
+            .unwrap_or_else(|err| format!("<unprintable workload: {}>", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DistributionConfig, WorkloadConfig, DEFAULT_PERSONA};
+
+    const WORKLOAD: &str = r#"
+        [actions.send_text]
+        weight = 6
+        think_time = { distribution = "exp", lambda = 0.2 }
+        message_length = { distribution = "constant", value = 5.0 }
+
+        [actions.look_at_room]
+        weight = 4
+
+        [personas.chatty]
+        ratio = 1
+        actions.send_text = { weight = 12 }
+
+        [personas.lurker]
+        ratio = 3
+        actions.send_text = { message_length = { distribution = "constant", value = 1.0 } }
+    "#;
+
+    fn invalid(workload: &str) -> String {
+        let workload: WorkloadConfig = toml::from_str(workload).unwrap();
+        workload
+            .validate(&["send_text", "look_at_room"])
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn validate() {
+        let workload: WorkloadConfig = toml::from_str(WORKLOAD).unwrap();
+        workload.validate(&["send_text", "look_at_room"]).unwrap();
+
+        assert!(invalid("actions.go_afk = { weight = 1 }").contains("unknown action 'go_afk'"));
+        assert!(invalid(
+            r#"actions.send_text = { weight = 1, think_time = { distribution = "exp", lambda = 0.0 } }"#
+        )
+        .contains("invalid distribution parameters"));
+        assert!(invalid(
+            r#"actions.send_text = { weight = 1, message_length = { distribution = "uniform", min = 5.0, max = 1.0 } }"#
+        )
+        .contains("invalid distribution parameters"));
+        assert!(invalid("actions.send_text = { weight = 0 }")
+            .contains("persona 'default' needs at least one action with a non-zero weight"));
+        assert!(
+            invalid("actions.send_text = { weight = 1 }\npersonas.bot = { ratio = 0 }")
+                .contains("at least one persona needs a non-zero ratio")
+        );
+        assert!(invalid(
+            "actions.send_text = { weight = 1 }\npersonas.bot = { actions.look_at_room = {} }"
+        )
+        .contains("action 'look_at_room' in persona 'bot' needs a weight"));
+    }
+
+    #[test]
+    fn assign_persona() {
+        let workload: WorkloadConfig = toml::from_str(WORKLOAD).unwrap();
+        let personas: Vec<&str> = (0..8).map(|slot| workload.assign_persona(slot)).collect();
+        assert_eq!(
+            personas,
+            ["chatty", "lurker", "lurker", "lurker", "chatty", "lurker", "lurker", "lurker"]
+        );
+
+        let workload = WorkloadConfig::default();
+        assert_eq!(workload.assign_persona(3), DEFAULT_PERSONA);
+    }
+
+    #[test]
+    fn persona_overrides_keep_top_level_settings() {
+        let workload: WorkloadConfig = toml::from_str(WORKLOAD).unwrap();
+        workload.validate(&["send_text", "look_at_room"]).unwrap();
+        let personas = workload.personas();
+
+        let chatty = personas.iter().find(|p| p.name == "chatty").unwrap();
+        let send_text = chatty.action("send_text").unwrap();
+        assert_eq!(send_text.weight, 12);
+        assert!(matches!(
+            send_text.think_time,
+            Some(DistributionConfig::Exp { lambda }) if lambda == 0.2
+        ));
+        assert!(matches!(
+            send_text.message_length,
+            Some(DistributionConfig::Constant { value }) if value == 5.0
+        ));
+        assert_eq!(chatty.action("look_at_room").unwrap().weight, 4);
+
+        let lurker = personas.iter().find(|p| p.name == "lurker").unwrap();
+        let send_text = lurker.action("send_text").unwrap();
+        assert_eq!(send_text.weight, 6);
+        assert!(send_text.think_time.is_some());
+        assert!(matches!(
+            send_text.message_length,
+            Some(DistributionConfig::Constant { value }) if value == 1.0
+        ));
+    }
+
+    #[test]
+    fn explicit_personas_dont_count_towards_ratios() {
+        let workload: WorkloadConfig = toml::from_str(WORKLOAD).unwrap();
+        let explicit = [
+            Some("chatty".to_owned()),
+            None,
+            Some("chatty".to_owned()),
+            None,
+            None,
+            None,
+        ];
+
+        assert_eq!(
+            workload.assign_personas(&explicit),
+            ["chatty", "chatty", "chatty", "lurker", "lurker", "lurker"]
+        );
+    }
+}